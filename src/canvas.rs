@@ -0,0 +1,953 @@
+use crate::CanvasClient;
+use serde::{Deserialize, Serialize};
+use globset::GlobSet;
+use ignore::gitignore::Gitignore;
+use indicatif::{MultiProgress, ProgressBar};
+use std::{collections::{HashMap, HashSet}, path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicU64, AtomicUsize}}, time::{Duration, Instant}};
+use tokio::sync::{mpsc::UnboundedSender, Mutex, OwnedSemaphorePermit, Semaphore};
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Credentials {
+    pub canvas_url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_url: Option<String>,
+    /// Missing when the token is kept in the system keyring
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub canvas_token: Option<String>,
+}
+
+/// A credential file holds the credentials of one canvas instance,
+/// or a list of profiles, e.g. one for the home university and one for an exchange
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum CredentialFile {
+    Single(Credentials),
+    Profiles(Vec<Profile>),
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Profile {
+    pub name: String,
+    #[serde(flatten)]
+    pub credentials: Credentials,
+}
+
+#[derive(Deserialize)]
+pub struct User {
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Course {
+    pub id: u32,
+    pub name: String,
+    pub course_code: String,
+    #[serde(default)]
+    pub enrollment_term_id: Option<u32>,
+    /// Only given when the courses are listed with `include[]=term`
+    #[serde(default)]
+    pub term: Option<Term>,
+    #[serde(default)]
+    pub workflow_state: Option<String>,
+    #[serde(default)]
+    pub default_view: Option<String>,
+}
+
+/// The courses canvas lists for the user, along with how many were listed without access to them
+#[derive(Default)]
+pub struct CourseList {
+    pub courses: Vec<Course>,
+    /// Courses outside of the dates they are open to students
+    pub access_restricted: usize,
+    /// Null entries, and courses missing the details needed to download them
+    pub inaccessible: usize,
+}
+
+/// All canvas tells about a course outside of the dates it is open to students
+#[derive(Default, Deserialize)]
+pub struct RestrictedCourse {
+    #[serde(default)]
+    pub access_restricted_by_date: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Term {
+    pub id: u32,
+    pub name: String,
+    pub start_at: Option<String>,
+    pub end_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CourseNickname {
+    pub course_id: u32,
+    pub nickname: String,
+}
+
+/// The parts of a single course that are only given when asked for with `include[]`
+#[derive(Deserialize)]
+pub struct CourseDetails {
+    #[serde(default)]
+    pub syllabus_body: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DiscussionTopic {
+    pub id: u32,
+    pub title: String,
+    /// Missing for topics that are locked
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<File>,
+    /// The number of replies, counting the replies to replies
+    #[serde(default)]
+    pub discussion_subentry_count: u32,
+    /// Missing for announcements that are scheduled but not posted yet
+    #[serde(default)]
+    pub posted_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct Page {
+    pub page_id: u32,
+    /// The page's slug, e.g. `week-1-notes`
+    pub url: String,
+    pub title: String,
+    /// Left out in page lists, and for pages that are locked or unpublished
+    #[serde(default)]
+    pub body: Option<String>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// An error response from the canvas api, e.g. `{"errors": [{"message": "Invalid access token."}]}`
+#[derive(Debug)]
+pub struct CanvasError {
+    pub status: reqwest::StatusCode,
+    /// Set when the body has a status of its own, e.g. `{"status": "unauthorized"}`
+    pub canvas_status: Option<String>,
+    pub messages: Vec<String>,
+}
+
+impl CanvasError {
+    /// Reads the error envelope from the body of an unsuccessful response
+    pub async fn from_response(resp: reqwest::Response) -> Self {
+        let status = resp.status();
+        // proxies in front of canvas can answer with html, which still leaves the status code to report
+        let body = resp.json::<serde_json::Value>().await.unwrap_or_default();
+        CanvasError::new(status, &body)
+    }
+
+    pub fn new(status: reqwest::StatusCode, body: &serde_json::Value) -> Self {
+        let message = |error: &serde_json::Value| error.get("message").and_then(|message| message.as_str()).map(String::from);
+        let messages = match body.get("errors") {
+            Some(serde_json::Value::Array(errors)) => errors.iter().filter_map(message).collect(),
+            // validation errors are grouped by the field they are about
+            Some(serde_json::Value::Object(fields)) => fields.values()
+                .filter_map(|errors| errors.as_array())
+                .flatten()
+                .filter_map(message)
+                .collect(),
+            _ => message(body).into_iter().collect(),
+        };
+        CanvasError {
+            status,
+            canvas_status: body.get("status").and_then(|status| status.as_str()).map(String::from),
+            messages,
+        }
+    }
+
+    /// Gives the response back if it was successful, otherwise reads the error from it
+    pub async fn check(resp: reqwest::Response) -> Result<reqwest::Response, CanvasError> {
+        if resp.status().is_success() {
+            Ok(resp)
+        } else {
+            Err(CanvasError::from_response(resp).await)
+        }
+    }
+
+    pub fn is_unauthorized(&self) -> bool {
+        self.status == reqwest::StatusCode::UNAUTHORIZED || self.canvas_status.as_deref() == Some("unauthorized")
+    }
+}
+
+impl std::fmt::Display for CanvasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "canvas responded with {}", self.status)?;
+        if let Some(canvas_status) = &self.canvas_status {
+            write!(f, " ({})", canvas_status)?;
+        }
+        if !self.messages.is_empty() {
+            write!(f, ": {}", self.messages.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CanvasError {}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Folder {
+    pub id: u32,
+    pub name: String,
+    pub folders_url: String,
+    pub files_url: String,
+    pub for_submissions: bool,
+    pub can_upload: bool,
+    pub parent_folder_id: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct File {
+    pub id: u32,
+    pub folder_id: u32,
+    pub display_name: String,
+    pub size: u64,
+    /// Empty while the upload is in progress, and for some deleted files that are still listed
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub url: String,
+    /// Missing for some files, which are then always downloaded with --since and keep their modified time
+    #[serde(default)]
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Set for files that are locked until a date or behind a module prerequisite
+    #[serde(default)]
+    pub locked_for_user: bool,
+    #[serde(default)]
+    pub hidden: bool,
+    /// Only given by some deployments, the download is checked against it when it is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+    #[serde(skip)]
+    pub filepath: std::path::PathBuf,
+}
+
+/// The folders and files listed while walking a course, kept for `--dump-metadata`
+#[derive(Default)]
+pub struct TraversedTree {
+    pub folders: Vec<Folder>,
+    pub files: Vec<File>,
+}
+
+impl TraversedTree {
+    /// Nests the files and folders under their parents, starting from the folders whose parent was not walked
+    pub fn into_folder_trees(self) -> Vec<FolderTree> {
+        fn build(folder: Folder, children: &mut HashMap<Option<u32>, Vec<Folder>>, files: &mut HashMap<u32, Vec<File>>) -> FolderTree {
+            let subfolders = children.remove(&Some(folder.id)).unwrap_or_default();
+            FolderTree {
+                files: files.remove(&folder.id).unwrap_or_default().into_iter()
+                    .map(|file| FileEntry { path: file.filepath.clone(), file })
+                    .collect(),
+                folders: subfolders.into_iter().map(|subfolder| build(subfolder, children, files)).collect(),
+                folder,
+            }
+        }
+
+        let folder_ids: HashSet<u32> = self.folders.iter().map(|folder| folder.id).collect();
+        let mut roots = Vec::new();
+        let mut children: HashMap<Option<u32>, Vec<Folder>> = HashMap::new();
+        for folder in self.folders {
+            if folder.parent_folder_id.is_some_and(|parent_folder_id| folder_ids.contains(&parent_folder_id)) {
+                children.entry(folder.parent_folder_id).or_default().push(folder);
+            } else {
+                roots.push(folder);
+            }
+        }
+        let mut files: HashMap<u32, Vec<File>> = HashMap::new();
+        for file in self.files {
+            files.entry(file.folder_id).or_default().push(file);
+        }
+        roots.sort_by_key(|folder| folder.id);
+        for subfolders in children.values_mut() {
+            subfolders.sort_by_key(|folder| folder.id);
+        }
+        for folder_files in files.values_mut() {
+            folder_files.sort_by_key(|file| file.id);
+        }
+        roots.into_iter().map(|root| build(root, &mut children, &mut files)).collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct FolderTree {
+    #[serde(flatten)]
+    pub folder: Folder,
+    pub files: Vec<FileEntry>,
+    pub folders: Vec<FolderTree>,
+}
+
+#[derive(Serialize)]
+pub struct FileEntry {
+    #[serde(flatten)]
+    pub file: File,
+    /// Where the file is saved in the destination folder
+    pub path: PathBuf,
+}
+
+#[derive(Serialize)]
+pub struct CourseTree {
+    #[serde(flatten)]
+    pub course: Course,
+    pub folders: Vec<FolderTree>,
+}
+
+/// What `--dump-metadata` writes, the folder picked with `--folder-id` is in `folders`
+#[derive(Default, Serialize)]
+pub struct MetadataDump {
+    pub courses: Vec<CourseTree>,
+    pub folders: Vec<FolderTree>,
+}
+
+/// A layout for the course files, e.g. `{term}/{course_code}/{folder_path}/{filename}`
+#[derive(Clone, Debug)]
+pub struct DestinationTemplate {
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Clone, Debug)]
+enum TemplatePart {
+    Literal(String),
+    CourseCode,
+    CourseName,
+    Term,
+    FolderPath,
+    Filename,
+}
+
+/// What the course placeholders of a [`DestinationTemplate`] stand for
+#[derive(Clone, Default)]
+pub struct TemplateValues {
+    pub course_code: String,
+    pub course_name: String,
+    pub term: String,
+    /// The folders a file is in on canvas are taken relative to this
+    pub course_folder_path: PathBuf,
+}
+
+impl std::str::FromStr for DestinationTemplate {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}')
+                .ok_or_else(|| format!("{} has a {{ that is not closed", template))?;
+            parts.push(match &rest[start + 1..start + end] {
+                "course_code" => TemplatePart::CourseCode,
+                "course_name" => TemplatePart::CourseName,
+                "term" => TemplatePart::Term,
+                "folder_path" => TemplatePart::FolderPath,
+                "filename" => TemplatePart::Filename,
+                placeholder => return Err(format!(
+                    "{{{}}} is not a placeholder, use {{course_code}}, {{course_name}}, {{term}}, {{folder_path}} or {{filename}}",
+                    placeholder)),
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("{} has a }} that is not opened", template));
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+        // every file would end up at the same path otherwise
+        if !parts.iter().any(|part| matches!(part, TemplatePart::Filename)) {
+            return Err(format!("{} has no {{filename}}", template));
+        }
+        Ok(DestinationTemplate { parts })
+    }
+}
+
+impl DestinationTemplate {
+    /// The path of a file relative to the destination folder, `folder_path` being the folder it is saved in without the template
+    pub fn expand(&self, values: &TemplateValues, folder_path: &Path, filename: &str) -> PathBuf {
+        let folder_path = folder_path.strip_prefix(&values.course_folder_path).unwrap_or(folder_path);
+        let mut expanded = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(literal) => expanded.push_str(literal),
+                TemplatePart::CourseCode => expanded.push_str(&sanitize_filename::sanitize(&values.course_code)),
+                TemplatePart::CourseName => expanded.push_str(&sanitize_filename::sanitize(&values.course_name)),
+                TemplatePart::Term => expanded.push_str(&sanitize_filename::sanitize(&values.term)),
+                // the folder names were sanitized already
+                TemplatePart::FolderPath => expanded.push_str(&folder_path.components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/")),
+                TemplatePart::Filename => expanded.push_str(filename),
+            }
+        }
+        // empty placeholders leave empty folder names behind, and the template should not lead out of the destination
+        expanded.split(['/', '\\'])
+            .filter(|name| !name.trim().is_empty() && *name != "." && *name != "..")
+            .collect()
+    }
+}
+
+fn null_as_empty<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+#[derive(Clone)]
+pub struct ProcessOptions {
+    pub canvas_client: CanvasClient,
+    pub link: String,
+    pub parent_folder_path: std::path::PathBuf,
+    pub files_to_download: Arc<Mutex<Vec<File>>>,
+    pub download_newer: bool,
+    pub max_files: Option<usize>,
+    pub seen_files: Arc<HashMap<u32, Option<std::path::PathBuf>>>,
+    pub move_existing: bool,
+    pub moved_files: Arc<Mutex<Vec<SeenFile>>>,
+    pub file_states: Arc<HashMap<u32, FileState>>,
+    pub file_queue: Option<FileQueue>,
+    pub folder_filters: Arc<Vec<Vec<String>>>,
+    pub relative_folder_path: Vec<String>,
+    pub folder_selected: bool,
+    /// Keep every file found, including those that are already up to date
+    pub diff: bool,
+    /// Leave the destination folder untouched
+    pub dry_run: bool,
+    pub canvas_ignore: Option<Arc<Gitignore>>,
+    pub include_globs: Arc<GlobSet>,
+    pub exclude_globs: Arc<GlobSet>,
+    /// Paths are matched against the canvas ignore and the include/exclude globs relative to this folder
+    pub ignore_root: PathBuf,
+    /// Files last updated before this are left out
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Files left out because they are already downloaded, only kept for the manifest
+    pub skipped_files: Option<Arc<std::sync::Mutex<Vec<File>>>>,
+    /// Lays the files out under the destination folder instead of the folders they are in on canvas
+    pub destination_template: Option<Arc<DestinationTemplate>>,
+    pub template_values: Arc<TemplateValues>,
+    pub destination_folder: PathBuf,
+    /// Every folder and file listed, only kept for `--dump-metadata`
+    pub traversed: Option<Arc<std::sync::Mutex<TraversedTree>>>,
+    /// Files left out because they are already downloaded, by id, so their copies in other folders are left out too
+    pub existing_files: Arc<std::sync::Mutex<HashMap<u32, PathBuf>>>,
+    /// Paths given to the files found so far in this run, ignoring case, so files with the same name can be told apart
+    pub claimed_paths: Arc<std::sync::Mutex<HashMap<String, u32>>>,
+    /// Files are put straight into this folder rather than the folder they are in on canvas
+    pub flatten_folder: Option<PathBuf>,
+    /// Keep the course's root folder, e.g. `course files`, as a folder of its own
+    pub keep_root_folder: bool,
+    /// Download files again even when they are up to date
+    pub force: bool,
+    pub max_file_size: Option<u64>,
+    pub min_file_size: Option<u64>,
+    /// Files left out by --max-file-size and --min-file-size
+    pub size_filtered_files: Arc<std::sync::Mutex<FileTally>>,
+    /// Keep files that are hidden from students
+    pub include_hidden: bool,
+    /// Folder and file names are shortened to this many characters
+    pub max_path_length: Option<usize>,
+    /// Limits how many listings are fetched at once, folders are walked one at a time without it
+    pub scan_permits: Option<Arc<Semaphore>>,
+    /// Told about every file handed to the file queue
+    pub on_event: Option<EventHandler>,
+}
+
+#[derive(Clone)]
+pub struct DownloadOptions {
+    pub canvas_token: String,
+    /// The canvas and api urls, whose hosts are the only ones the token is sent to
+    pub token_urls: Vec<reqwest::Url>,
+    pub client: reqwest::Client,
+    pub progress_bars: Arc<MultiProgress>,
+    pub downloaded_file_ids: Arc<Mutex<HashSet<u32>>>,
+    pub failed_downloads: Arc<Mutex<Vec<FailedDownload>>>,
+    pub empty_downloads: Arc<AtomicUsize>,
+    pub timings: Option<Arc<Mutex<Vec<FileTiming>>>>,
+    pub checksums: Option<Arc<Mutex<HashMap<std::path::PathBuf, String>>>>,
+    pub host_limiter: HostLimiter,
+    pub speed_limiter: SpeedLimiter,
+    pub circuit_breaker: CircuitBreaker,
+    pub queue_file: Option<QueueFile>,
+    pub segments: usize,
+    pub retries: u32,
+    pub resume: bool,
+    pub download_records: Option<Arc<Mutex<Vec<DownloadRecord>>>>,
+    pub rate_limiter: RateLimiter,
+    /// How long to wait for a response, or for the next chunk of a file
+    pub timeout: Duration,
+    /// Paths that were given the extension from the download's Content-Disposition, by file id
+    pub renamed_files: Arc<Mutex<HashMap<u32, PathBuf>>>,
+    /// Fail downloads that do not match their md5, rather than only removing them
+    pub verify: bool,
+    /// Bytes transferred by all downloads, for the summary at the end
+    pub downloaded_bytes: Arc<AtomicU64>,
+    /// The ETag the host gave for each file by id, sent back with `If-None-Match` so unchanged files are not downloaded again
+    pub etags: Arc<std::sync::Mutex<HashMap<u32, String>>>,
+    /// Off with `--force`, which downloads the files again even when they did not change
+    pub send_etags: bool,
+    /// Files whose copy on disk the host said is still current
+    pub not_modified_file_ids: Arc<Mutex<HashSet<u32>>>,
+    /// Told how each download is going
+    pub on_event: Option<EventHandler>,
+}
+
+/// What happens to the files as they are found and downloaded, e.g. `{"event":"done","id":1234}` as json
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    Discovered { file: &'a File, path: &'a Path },
+    Progress { id: u32, bytes: u64, total: u64 },
+    Done { id: u32 },
+    NotModified { id: u32 },
+    Failed { id: u32, error: String },
+    Summary { downloaded: usize, skipped: usize, failed: usize, bytes: u64, seconds: f64 },
+}
+
+/// Called with every event, from whichever task the event happened in
+pub type EventHandler = Arc<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct FileTally {
+    pub count: usize,
+    pub bytes: u64,
+}
+
+// hands files to the download workers as soon as they are found
+#[derive(Clone)]
+pub struct FileQueue {
+    sender: UnboundedSender<File>,
+    progress_bar: ProgressBar,
+    queue_file: Option<QueueFile>,
+    queued_file_ids: Arc<std::sync::Mutex<HashSet<u32>>>,
+}
+
+impl FileQueue {
+    pub fn new(sender: UnboundedSender<File>, progress_bar: ProgressBar, queue_file: Option<QueueFile>) -> Self {
+        FileQueue { sender, progress_bar, queue_file, queued_file_ids: Default::default() }
+    }
+
+    /// Gives false when the file was already queued
+    pub fn push(&self, file: File) -> bool {
+        // a file shown in several folders is only downloaded once
+        if !self.queued_file_ids.lock().unwrap().insert(file.id) {
+            return false;
+        }
+        self.progress_bar.inc_length(1);
+        // the file has to be in the queue file before a worker can mark it done
+        if let Some(queue_file) = &self.queue_file {
+            if let Err(e) = queue_file.add(std::slice::from_ref(&file)) {
+                tracing::warn!(file = %file.display_name, "failed to add the file to the queue file\n{:?}", e);
+            }
+        }
+        // the workers only stop after the queue is closed, so the receiver is still around
+        let _ = self.sender.send(file);
+        true
+    }
+}
+
+pub struct FailedDownload {
+    pub file: File,
+    pub error: anyhow::Error,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DownloadStatus {
+    Downloaded,
+    SkippedExisting,
+    /// The host answered that the copy on disk is still current
+    NotModified,
+    Failed,
+}
+
+impl DownloadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DownloadStatus::Downloaded => "downloaded",
+            DownloadStatus::SkippedExisting => "skipped-existing",
+            DownloadStatus::NotModified => "not-modified",
+            DownloadStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DownloadRecord {
+    pub id: u32,
+    pub display_name: String,
+    pub filepath: PathBuf,
+    pub size: u64,
+    pub url: String,
+    pub status: DownloadStatus,
+}
+
+impl DownloadRecord {
+    pub fn new(file: &File, status: DownloadStatus) -> Self {
+        DownloadRecord {
+            id: file.id,
+            display_name: file.display_name.clone(),
+            filepath: file.filepath.clone(),
+            size: file.size,
+            url: file.url.clone(),
+            status,
+        }
+    }
+}
+
+pub struct FileTiming {
+    pub id: u32,
+    pub filepath: std::path::PathBuf,
+    pub host: String,
+    pub bytes: u64,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub finished_at: chrono::DateTime<chrono::Utc>,
+    pub duration: std::time::Duration,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct FileState {
+    pub id: u32,
+    pub size: u64,
+    #[serde(default)]
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub filepath: std::path::PathBuf,
+}
+
+// file urls usually redirect to a separate file storage host, which has its own throttling
+#[derive(Clone)]
+pub struct HostLimiter {
+    limit: usize,
+    semaphores: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl HostLimiter {
+    pub fn new(limit: usize) -> Self {
+        HostLimiter {
+            limit,
+            semaphores: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn acquire(&self, url: &reqwest::Url) -> OwnedSemaphorePermit {
+        let host = url.host_str().unwrap_or_default().to_string();
+        let semaphore = self.semaphores.lock().await
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone();
+        // the semaphore is never closed
+        semaphore.acquire_owned().await.unwrap()
+    }
+}
+
+// shares --max-speed between the downloads, by giving each chunk a slot as long as it takes to arrive at that speed
+#[derive(Clone, Default)]
+pub struct SpeedLimiter {
+    bytes_per_second: Option<u64>,
+    next_slot: Arc<std::sync::Mutex<Option<Instant>>>,
+}
+
+const SPEED_LIMIT_BURST: Duration = Duration::from_millis(100);
+
+impl SpeedLimiter {
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        SpeedLimiter {
+            // a speed of 0 would never let anything through
+            bytes_per_second: bytes_per_second.filter(|bytes_per_second| *bytes_per_second > 0),
+            next_slot: Arc::new(std::sync::Mutex::new(None)),
+        }
+    }
+
+    /// Waits until the chunk fits within the speed limit
+    pub async fn consume(&self, bytes: usize) {
+        let bytes_per_second = match self.bytes_per_second {
+            Some(bytes_per_second) => bytes_per_second,
+            None => return,
+        };
+        let slot_end = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            // a little time spent idle is made up for, as waking up late after every chunk would otherwise add up
+            let now = Instant::now();
+            let earliest = now.checked_sub(SPEED_LIMIT_BURST).unwrap_or(now);
+            let slot_start = next_slot.map_or(now, |next_slot| next_slot.max(earliest));
+            let slot_end = slot_start + Duration::from_secs_f64(bytes as f64 / bytes_per_second as f64);
+            *next_slot = Some(slot_end);
+            slot_end
+        };
+        // sleeping always takes at least a tick of the timer, even when the slot has already passed
+        if slot_end > Instant::now() {
+            tokio::time::sleep_until(slot_end.into()).await;
+        }
+    }
+}
+
+// canvas gives every token a bucket of request cost that refills over time, and reports what is left of it
+// in the X-Rate-Limit-Remaining header, so new requests are held back for a moment when it runs low
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    paused_until: Arc<std::sync::Mutex<Option<Instant>>>,
+    requests_per_second: Option<Arc<governor::DefaultDirectRateLimiter>>,
+}
+
+// a full bucket holds 700
+const RATE_LIMIT_LOW: f64 = 100.0;
+const RATE_LIMIT_LOW_PAUSE: Duration = Duration::from_secs(1);
+/// After this many rate limited attempts in a row the response is handed back as is
+pub const MAX_RATE_LIMITED_ATTEMPTS: u32 = 8;
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter::default()
+    }
+
+    /// Also keeps every request, listing or download, to this many a second
+    pub fn with_requests_per_second(requests_per_second: std::num::NonZeroU32) -> Self {
+        RateLimiter {
+            requests_per_second: Some(Arc::new(governor::RateLimiter::direct(governor::Quota::per_second(requests_per_second)))),
+            ..RateLimiter::default()
+        }
+    }
+
+    /// Waits until requests are allowed again
+    pub async fn wait(&self) {
+        let paused_until = *self.paused_until.lock().unwrap();
+        if let Some(paused_until) = paused_until {
+            tokio::time::sleep_until(paused_until.into()).await;
+        }
+        if let Some(requests_per_second) = &self.requests_per_second {
+            requests_per_second.until_ready().await;
+        }
+    }
+
+    pub fn record(&self, headers: &reqwest::header::HeaderMap) {
+        if remaining(headers).is_some_and(|remaining| remaining < RATE_LIMIT_LOW) {
+            self.pause(RATE_LIMIT_LOW_PAUSE);
+        }
+    }
+
+    /// Canvas answers with 403 Forbidden (Rate Limit Exceeded) once the bucket is empty
+    pub fn is_rate_limited(resp: &reqwest::Response) -> bool {
+        resp.status() == reqwest::StatusCode::FORBIDDEN && remaining(resp.headers()).is_some_and(|remaining| remaining < 1.0)
+    }
+
+    /// Holds back every request for longer with each rate limited attempt in a row, to let the bucket refill
+    pub fn back_off(&self, attempt: u32) -> Duration {
+        let pause = Duration::from_secs(5 * 2u64.pow(attempt.saturating_sub(1).min(4)));
+        self.pause(pause);
+        pause
+    }
+
+    fn pause(&self, pause: Duration) {
+        let mut paused_until = self.paused_until.lock().unwrap();
+        let until = Instant::now() + pause;
+        if paused_until.is_none_or(|paused_until| paused_until < until) {
+            *paused_until = Some(until);
+        }
+    }
+}
+
+fn remaining(headers: &reqwest::header::HeaderMap) -> Option<f64> {
+    headers.get("X-Rate-Limit-Remaining")?.to_str().ok()?.trim().parse().ok()
+}
+
+// a host that keeps failing is skipped for a while, so the run can finish instead of waiting on it
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    threshold: usize,
+    cooldown: Duration,
+    hosts: Arc<std::sync::Mutex<HashMap<String, HostCircuit>>>,
+}
+
+#[derive(Default)]
+struct HostCircuit {
+    consecutive_failures: usize,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            hosts: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn allow(&self, url: &reqwest::Url) -> bool {
+        let hosts = self.hosts.lock().unwrap();
+        match hosts.get(url.host_str().unwrap_or_default()).and_then(|circuit| circuit.open_until) {
+            Some(open_until) => Instant::now() >= open_until,
+            None => true,
+        }
+    }
+
+    // only server errors and throttling say something about the health of the host
+    pub fn record_status(&self, url: &reqwest::Url, status: reqwest::StatusCode) {
+        if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            self.record_failure(url);
+        } else {
+            self.record_success(url);
+        }
+    }
+
+    pub fn record_success(&self, url: &reqwest::Url) {
+        let host = url.host_str().unwrap_or_default();
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(circuit) = hosts.remove(host) {
+            if circuit.open_until.is_some() {
+                tracing::info!(host, "responding again");
+            }
+        }
+    }
+
+    pub fn record_failure(&self, url: &reqwest::Url) {
+        let host = url.host_str().unwrap_or_default();
+        let mut hosts = self.hosts.lock().unwrap();
+        let circuit = hosts.entry(host.to_string()).or_default();
+        circuit.consecutive_failures += 1;
+        if circuit.consecutive_failures >= self.threshold {
+            if circuit.open_until.is_none_or(|open_until| Instant::now() >= open_until) {
+                tracing::warn!(host, "failed {} times in a row, skipping it for {}s", circuit.consecutive_failures, self.cooldown.as_secs());
+            }
+            circuit.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct SeenFile {
+    pub id: u32,
+    #[serde(default)]
+    pub filepath: Option<std::path::PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum SeenIndexEntry {
+    Id(u32),
+    File(SeenFile),
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueStatus {
+    Pending,
+    Done,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct QueueEntry {
+    #[serde(flatten)]
+    pub file: File,
+    pub filepath: PathBuf,
+    pub status: QueueStatus,
+}
+
+// changes are appended to a log next to the queue file, so a killed run leaves it up to date
+// without writing the whole queue out for every file. The log is folded into the queue by `save`
+#[derive(Clone)]
+pub struct QueueFile {
+    path: PathBuf,
+    entries: Arc<std::sync::Mutex<Vec<QueueEntry>>>,
+    log: Arc<std::sync::Mutex<Option<std::fs::File>>>,
+}
+
+impl QueueFile {
+    pub fn new(path: PathBuf, entries: Vec<QueueEntry>) -> Self {
+        QueueFile { path, entries: Arc::new(std::sync::Mutex::new(entries)), log: Default::default() }
+    }
+
+    /// The log of changes made since the queue file was last saved
+    pub fn log_path(path: &Path) -> PathBuf {
+        let mut log_path = path.as_os_str().to_owned();
+        log_path.push(".log");
+        PathBuf::from(log_path)
+    }
+
+    /// Applies the changes logged by a run that did not get to save the queue
+    pub fn replay_log(path: &Path, entries: &mut Vec<QueueEntry>) -> anyhow::Result<()> {
+        let log = match std::fs::read_to_string(Self::log_path(path)) {
+            Ok(log) => log,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        // the last line is cut short when the run was killed mid write
+        for logged in log.lines().filter_map(|line| serde_json::from_str::<QueueEntry>(line).ok()) {
+            match entries.iter_mut().find(|entry| entry.file.id == logged.file.id) {
+                Some(entry) => *entry = logged,
+                None => entries.push(logged),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pending(&self) -> Vec<File> {
+        self.entries.lock().unwrap().iter()
+            .filter(|entry| entry.status == QueueStatus::Pending)
+            .map(|entry| File { filepath: entry.filepath.clone(), ..entry.file.clone() })
+            .collect()
+    }
+
+    pub fn add(&self, files: &[File]) -> anyhow::Result<()> {
+        let added: Vec<_> = files.iter().map(|file| QueueEntry {
+            file: file.clone(),
+            filepath: file.filepath.clone(),
+            status: QueueStatus::Pending,
+        }).collect();
+        self.append(&added)?;
+        self.entries.lock().unwrap().extend(added);
+        Ok(())
+    }
+
+    pub fn mark_done(&self, id: u32) -> anyhow::Result<()> {
+        let done: Vec<_> = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.iter_mut()
+                .filter(|entry| entry.file.id == id)
+                .map(|entry| {
+                    entry.status = QueueStatus::Done;
+                    entry.clone()
+                })
+                .collect()
+        };
+        self.append(&done)
+    }
+
+    /// Writes out the whole queue and starts a new log
+    pub fn save(&self) -> anyhow::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        Self::write(&self.path, &self.entries.lock().unwrap())?;
+        *log = None;
+        match std::fs::remove_file(Self::log_path(&self.path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // one line of json per entry, written in a single call so lines from different workers do not interleave
+    fn append(&self, entries: &[QueueEntry]) -> anyhow::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut lines = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        let mut log = self.log.lock().unwrap();
+        let log = match &mut *log {
+            Some(log) => log,
+            None => log.insert(std::fs::OpenOptions::new().create(true).append(true).open(Self::log_path(&self.path))?),
+        };
+        std::io::Write::write_all(log, &lines)?;
+        Ok(())
+    }
+
+    // written next to the queue file and renamed over it, so the file is never half written
+    fn write(path: &Path, entries: &[QueueEntry]) -> anyhow::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(entries)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
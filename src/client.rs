@@ -0,0 +1,291 @@
+use crate::canvas;
+use anyhow::{Context, Result};
+use reqwest::header;
+use serde::de::DeserializeOwned;
+
+/// A logged in connection to the canvas api.
+#[derive(Clone)]
+pub struct CanvasClient {
+    client: reqwest::Client,
+    api_url: String,
+    canvas_token: String,
+    rate_limiter: canvas::RateLimiter,
+    per_page: u32,
+}
+
+/// The most items canvas hands out in a page
+pub const MAX_PER_PAGE: u32 = 100;
+
+impl CanvasClient {
+    /// `api_url` is the root of the canvas instance, e.g. `https://canvas.example.com`
+    pub fn new(client: reqwest::Client, api_url: impl Into<String>, canvas_token: impl Into<String>) -> Self {
+        CanvasClient {
+            client,
+            api_url: api_url.into().trim_end_matches('/').to_string(),
+            canvas_token: canvas_token.into(),
+            rate_limiter: canvas::RateLimiter::new(),
+            per_page: MAX_PER_PAGE,
+        }
+    }
+
+    /// How many items lists are fetched with a page, at most [`MAX_PER_PAGE`]
+    pub fn with_per_page(mut self, per_page: u32) -> Self {
+        self.per_page = per_page.clamp(1, MAX_PER_PAGE);
+        self
+    }
+
+    /// Replaces the client's rate limiter, e.g. with one that also caps the requests per second
+    pub fn with_rate_limiter(mut self, rate_limiter: canvas::RateLimiter) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    pub fn api_url(&self) -> &str {
+        &self.api_url
+    }
+
+    pub fn canvas_token(&self) -> &str {
+        &self.canvas_token
+    }
+
+    /// Shared by every clone of the client, so downloads can hold back along with the api requests
+    pub fn rate_limiter(&self) -> &canvas::RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Sends an authenticated GET, waiting out canvas' rate limit
+    pub async fn get(&self, link: &str) -> Result<reqwest::Response> {
+        let mut attempt = 0;
+        loop {
+            self.rate_limiter.wait().await;
+            let resp = self.client.get(link)
+                .bearer_auth(&self.canvas_token)
+                .send()
+                .await
+                .with_context(|| format!("Something went wrong when reaching {}", link))?;
+            self.rate_limiter.record(resp.headers());
+            if !canvas::RateLimiter::is_rate_limited(&resp) || attempt >= canvas::MAX_RATE_LIMITED_ATTEMPTS {
+                return Ok(resp);
+            }
+            attempt += 1;
+            let pause = self.rate_limiter.back_off(attempt);
+            tracing::info!(link, "rate limited by canvas, waiting {}s", pause.as_secs());
+        }
+    }
+
+    /// The user the token belongs to
+    pub async fn user(&self) -> Result<canvas::User> {
+        let user_link = format!("{}/api/v1/users/self", self.api_url);
+        let resp = self.get(&user_link).await?;
+        let resp = canvas::CanvasError::check(resp).await?;
+
+        resp.json::<canvas::User>()
+            .await
+            .with_context(|| format!("{} did not return a user, check that the canvas url is correct", &user_link))
+    }
+
+    /// The courses the user is enrolled in that are available to them, including concluded enrollments
+    pub async fn list_courses(&self) -> Result<canvas::CourseList> {
+        self.list_courses_in_states(&[], None).await
+    }
+
+    /// The courses the user is enrolled in that are in one of these workflow states, e.g. `completed`,
+    /// and where the user's enrollment is in `enrollment_state`, e.g. `active`
+    pub async fn list_courses_in_states(&self, states: &[&str], enrollment_state: Option<&str>) -> Result<canvas::CourseList> {
+        let mut courses_link = format!("{}/api/v1/courses?include[]=term", self.api_url);
+        if let Some(enrollment_state) = enrollment_state {
+            courses_link.push_str("&enrollment_state=");
+            courses_link.push_str(enrollment_state);
+        }
+        for state in states {
+            courses_link.push_str("&state[]=");
+            courses_link.push_str(state);
+        }
+        // do not directly deserialize into canvas::Course objects
+        // there are may be courses that are restricted and not contain the fields needed to deserialise
+        let courses_json = self.fetch_all_pages::<serde_json::Value>(&courses_link).await?;
+
+        let mut course_list = canvas::CourseList::default();
+        for course_json in courses_json {
+            let restricted: canvas::RestrictedCourse = serde_json::from_value(course_json.clone()).unwrap_or_default();
+            if restricted.access_restricted_by_date {
+                course_list.access_restricted += 1;
+            } else if course_json.get("enrollments").is_some() {
+                let course: canvas::Course = serde_json::from_value(course_json)
+                    .context("Failed to deserialize course")?;
+                course_list.courses.push(course);
+            } else {
+                // concluded and restricted enrollments can come back as null
+                course_list.inaccessible += 1;
+            }
+        }
+        Ok(course_list)
+    }
+
+    pub async fn list_course_nicknames(&self) -> Result<Vec<canvas::CourseNickname>> {
+        let nicknames_link = format!("{}/api/v1/users/self/course_nicknames", self.api_url);
+        self.fetch_all_pages(&nicknames_link).await
+    }
+
+    /// Every folder in the course, including the root folder
+    pub async fn list_folders(&self, course_id: u32) -> Result<Vec<canvas::Folder>> {
+        let folders_link = format!("{}/api/v1/courses/{}/folders", self.api_url, course_id);
+        self.fetch_all_pages(&folders_link).await
+    }
+
+    /// A single folder, from any course the user has access to
+    pub async fn folder(&self, folder_id: u32) -> Result<canvas::Folder> {
+        let folder_link = format!("{}/api/v1/folders/{}", self.api_url, folder_id);
+        let resp = self.get(&folder_link).await?;
+        let resp = canvas::CanvasError::check(resp).await?;
+
+        resp.json::<canvas::Folder>()
+            .await
+            .with_context(|| format!("{} did not return a folder", &folder_link))
+    }
+
+    /// The files directly inside the folder
+    pub async fn list_files(&self, folder_id: u32) -> Result<Vec<canvas::File>> {
+        let files_link = format!("{}/api/v1/folders/{}/files", self.api_url, folder_id);
+        self.fetch_all_pages(&files_link).await
+    }
+
+    /// Fetches every page of a list endpoint, following the `Link` headers canvas paginates with.
+    pub async fn fetch_all_pages<T: DeserializeOwned>(&self, link: &str) -> Result<Vec<T>> {
+        let mut items = Vec::new();
+        // canvas defaults to 10 items a page, the next links keep whatever page size the first request asked for
+        let mut next_link = Some(if link.contains("per_page=") {
+            link.to_string()
+        } else if link.contains('?') {
+            format!("{}&per_page={}", link, self.per_page)
+        } else {
+            format!("{}?per_page={}", link, self.per_page)
+        });
+        let mut pages = 0;
+        while let Some(link) = next_link {
+            pages += 1;
+            let resp = self.get(&link).await?;
+            next_link = next_page_link(resp.headers());
+
+            let status = resp.status();
+            let resp = canvas::CanvasError::check(resp)
+                .await
+                .with_context(|| format!("Failed to list {}", link))?;
+            let page = resp.json::<serde_json::Value>()
+                .await
+                .with_context(|| format!("{} did not return json", &link))?;
+            let page = match page {
+                serde_json::Value::Array(page) => page,
+                // canvas answers some list requests with {"status": "unauthorized"} instead of a list
+                serde_json::Value::Object(ref body) if body.get("status").is_some_and(|status| status.is_string()) || body.contains_key("errors") => {
+                    return Err(canvas::CanvasError::new(status, &page)).with_context(|| format!("Failed to list {}", link));
+                },
+                _ => {
+                    anyhow::bail!("Unexpected response from {}\n{}", link, page);
+                },
+            };
+            for item in page {
+                items.push(serde_json::from_value(item)
+                    .with_context(|| format!("Failed to deserialize response from {}", link))?);
+            }
+        }
+        tracing::debug!(link, pages, items = items.len(), "listed");
+        Ok(items)
+    }
+}
+
+// the next link has to be followed as is, since some endpoints paginate with opaque bookmarks
+// instead of page numbers, e.g. <https://canvas.example.com/api/v1/courses?page=bookmark:WzEwXQ>; rel="next"
+fn next_page_link(headers: &header::HeaderMap) -> Option<String> {
+    for value in headers.get_all(header::LINK) {
+        let mut rest = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        while let Some(start) = rest.find('<') {
+            let end = start + rest[start..].find('>')?;
+            let link = &rest[start + 1..end];
+            rest = &rest[end + 1..];
+
+            // the parameters of this link run until the next link starts
+            let params = &rest[..rest.find('<').unwrap_or(rest.len())];
+            let is_next = params.split(';')
+                .filter_map(|param| param.split_once('='))
+                .filter(|(name, _)| name.trim().eq_ignore_ascii_case("rel"))
+                .any(|(_, rel)| rel.trim().trim_end_matches(',').trim().trim_matches('"')
+                    .split_whitespace()
+                    .any(|rel| rel.eq_ignore_ascii_case("next")));
+            if is_next {
+                return Some(link.to_string());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::next_page_link;
+    use reqwest::header::{self, HeaderMap, HeaderValue};
+
+    fn link_headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::LINK, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn picks_next_out_of_a_full_link_header() {
+        let headers = link_headers(&[concat!(
+            "<https://canvas.example.com/api/v1/courses?page=1&per_page=10>; rel=\"current\",",
+            "<https://canvas.example.com/api/v1/courses?page=1&per_page=10>; rel=\"first\",",
+            "<https://canvas.example.com/api/v1/courses?page=1&per_page=10>; rel=\"prev\",",
+            "<https://canvas.example.com/api/v1/courses?page=3&per_page=10>; rel=\"next\",",
+            "<https://canvas.example.com/api/v1/courses?page=5&per_page=10>; rel=\"last\"",
+        )]);
+
+        assert_eq!(next_page_link(&headers).as_deref(), Some("https://canvas.example.com/api/v1/courses?page=3&per_page=10"));
+    }
+
+    #[test]
+    fn reads_quoted_and_unquoted_rels() {
+        let quoted = link_headers(&["<https://canvas.example.com/a?page=2>; rel=\"next\""]);
+        let unquoted = link_headers(&["<https://canvas.example.com/a?page=2>; rel=next"]);
+        let spaced = link_headers(&["<https://canvas.example.com/a?page=2> ; REL = next"]);
+
+        for headers in [quoted, unquoted, spaced] {
+            assert_eq!(next_page_link(&headers).as_deref(), Some("https://canvas.example.com/a?page=2"));
+        }
+    }
+
+    #[test]
+    fn reads_next_out_of_several_rels() {
+        let headers = link_headers(&["<https://canvas.example.com/a?page=1>; rel=\"first\", <https://canvas.example.com/a?page=2>; rel=\"next last\""]);
+
+        assert_eq!(next_page_link(&headers).as_deref(), Some("https://canvas.example.com/a?page=2"));
+    }
+
+    #[test]
+    fn keeps_bookmark_links_as_they_are() {
+        let headers = link_headers(&[
+            "<https://canvas.example.com/api/v1/courses?page=first&per_page=10>; rel=\"first\"",
+            "<https://canvas.example.com/api/v1/courses?page=bookmark:WzEwXQ&per_page=10>; rel=\"next\"",
+        ]);
+
+        assert_eq!(next_page_link(&headers).as_deref(), Some("https://canvas.example.com/api/v1/courses?page=bookmark:WzEwXQ&per_page=10"));
+    }
+
+    #[test]
+    fn gives_none_on_the_last_page() {
+        let headers = link_headers(&["<https://canvas.example.com/a?page=1>; rel=\"first\", <https://canvas.example.com/a?page=5>; rel=\"last\""]);
+
+        assert_eq!(next_page_link(&headers), None);
+        assert_eq!(next_page_link(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{CommandLineOptions, ExitStatus};
+use anyhow::{Context, Result};
+use clap::{ArgMatches, ValueSource};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+const CONFIG_FILE_NAME: &str = "canvas-downloader.toml";
+
+/// Defaults read from `canvas-downloader.toml`, for options that would otherwise be passed on every run.
+///
+/// Keys are named after the command line flags, e.g. `destination-folder = "~/courses"`.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    destination_folder: Option<PathBuf>,
+    max_concurrent_downloads: Option<std::num::NonZeroUsize>,
+    include: Option<Vec<String>>,
+    exclude: Option<Vec<String>>,
+    front_page: Option<bool>,
+    media: Option<bool>,
+    modules: Option<bool>,
+    pages: Option<bool>,
+    syllabus: Option<bool>,
+    discussions: Option<bool>,
+    announcements: Option<bool>,
+}
+
+// the working directory comes first, so a project folder can override the user's defaults
+fn config_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(CONFIG_FILE_NAME)];
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join("canvas-downloader").join(CONFIG_FILE_NAME));
+    }
+    paths
+}
+
+pub fn load_config() -> Result<Option<Config>> {
+    let path = match config_paths().into_iter().find(|path| path.is_file()) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read config file: {}", path.to_string_lossy()))?;
+    let config = toml::from_str(&contents)
+        .with_context(|| format!("Config file is not valid: {}", path.to_string_lossy()))
+        .context(ExitStatus::BadArguments)?;
+    Ok(Some(config))
+}
+
+impl Config {
+    /// Fills in the options that were not given on the command line
+    pub fn merge_into(self, args: &mut CommandLineOptions, matches: &ArgMatches) {
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if let Some(destination_folder) = self.destination_folder.filter(|_| !given("destination-folder")) {
+            args.destination_folder = destination_folder;
+        }
+        if let Some(max_concurrent_downloads) = self.max_concurrent_downloads.filter(|_| !given("max-concurrent-downloads")) {
+            args.max_concurrent_downloads = Some(max_concurrent_downloads);
+        }
+        if let Some(include) = self.include.filter(|_| !given("include")) {
+            args.include = include;
+        }
+        if let Some(exclude) = self.exclude.filter(|_| !given("exclude")) {
+            args.exclude = exclude;
+        }
+        for (enabled, flag, id) in [
+            (self.front_page, &mut args.front_page, "front-page"),
+            (self.media, &mut args.media, "media"),
+            (self.modules, &mut args.modules, "modules"),
+            (self.pages, &mut args.pages, "pages"),
+            (self.syllabus, &mut args.syllabus, "syllabus"),
+            (self.discussions, &mut args.discussions, "discussions"),
+            (self.announcements, &mut args.announcements, "announcements"),
+        ] {
+            if let Some(enabled) = enabled.filter(|_| !given(id)) {
+                *flag = enabled;
+            }
+        }
+    }
+}
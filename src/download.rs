@@ -0,0 +1,666 @@
+use crate::canvas::{self, DownloadOptions, Event, RateLimiter};
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use md5::Md5;
+use rand::Rng;
+use reqwest::header;
+use sha2::{Digest, Sha256};
+use std::{io::{Seek, Write}, path::PathBuf, sync::{Arc, atomic::Ordering}};
+use tokio::sync::{mpsc::UnboundedReceiver, Mutex};
+use tracing::{debug, warn};
+
+/// Downloads the files, `workers` at a time, counting each one on `overall_progress` as it finishes
+pub async fn download_files(options: &DownloadOptions, files: &[canvas::File], workers: usize, overall_progress: &ProgressBar) {
+    // each file is picked up as soon as a download finishes, so one slow file does not hold up the rest
+    futures::stream::iter(files)
+        .map(|canvas_file| async {
+            download_and_record(options, canvas_file).await;
+            record_overall_progress(overall_progress, options);
+        })
+        .buffer_unordered(workers)
+        .collect::<()>()
+        .await;
+}
+
+/// Starts `workers` tasks that download the files sent to them, until every sender is dropped
+pub fn spawn_download_workers(options: &DownloadOptions, receiver: UnboundedReceiver<canvas::File>, workers: usize, overall_progress: &ProgressBar) -> Vec<tokio::task::JoinHandle<()>> {
+    let receiver = Arc::new(Mutex::new(receiver));
+    (0..workers).map(|_| {
+        let options = options.clone();
+        let receiver = receiver.clone();
+        let overall_progress = overall_progress.clone();
+        tokio::spawn(async move {
+            loop {
+                // the lock is only held while waiting for the next file
+                let canvas_file = match receiver.lock().await.recv().await {
+                    Some(canvas_file) => canvas_file,
+                    None => break,
+                };
+                download_and_record(&options, &canvas_file).await;
+                record_overall_progress(&overall_progress, &options);
+            }
+        })
+    }).collect()
+}
+
+fn emit(options: &DownloadOptions, event: Event) {
+    if let Some(on_event) = &options.on_event {
+        on_event(&event);
+    }
+}
+
+// the timeout is for each chunk rather than the whole file, so large files on a slow connection still finish
+async fn stream_to_file(options: &DownloadOptions, file_response: &mut reqwest::Response, file: &mut std::fs::File, progress_bar: &ProgressBar, hasher: &mut FileHasher) -> Result<u64> {
+    let mut bytes = 0;
+    while let Some(chunk) = next_chunk(file_response, options.timeout).await? {
+        options.speed_limiter.consume(chunk.len()).await;
+        progress_bar.inc(chunk.len() as u64);
+        bytes += chunk.len() as u64;
+        hasher.write_all(&chunk)?;
+        file.write_all(&chunk)?;
+    }
+    Ok(bytes)
+}
+
+// the checksums taken of a file as it is written, the sha256 for --bagit and the md5 when canvas has one to compare with
+struct FileHasher {
+    sha256: Option<Sha256>,
+    md5: Option<Md5>,
+}
+
+impl FileHasher {
+    fn new(options: &DownloadOptions, canvas_file: &canvas::File) -> Self {
+        FileHasher {
+            sha256: options.checksums.as_ref().map(|_| Sha256::new()),
+            md5: canvas_file.md5.as_ref().map(|_| Md5::new()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sha256.is_none() && self.md5.is_none()
+    }
+}
+
+impl Write for FileHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(buf);
+        }
+        if let Some(md5) = &mut self.md5 {
+            md5.update(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct ChecksumMismatch {
+    expected: String,
+    actual: String,
+}
+
+impl std::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the md5 of the download is {} but canvas has {}", self.actual, self.expected)
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+// waits at most the timeout for the next chunk, so a stalled download does not hang forever
+async fn next_chunk(resp: &mut reqwest::Response, timeout: std::time::Duration) -> Result<Option<bytes::Bytes>> {
+    let chunk = tokio::time::timeout(timeout, resp.chunk())
+        .await
+        .with_context(|| format!("No data received for {}s", timeout.as_secs()))??;
+    Ok(chunk)
+}
+
+// connection errors, 5xx and 429 responses are usually gone after a moment, so the request is sent again
+async fn send_with_retries(options: &DownloadOptions, request: reqwest::RequestBuilder, progress_bar: Option<&ProgressBar>, display_name: &str) -> Result<reqwest::Response> {
+    let mut attempt = 0;
+    let mut rate_limited_attempt = 0;
+    loop {
+        options.rate_limiter.wait().await;
+        let sent = tokio::time::timeout(options.timeout, request.try_clone()
+            .expect("requests without a streamed body can be cloned")
+            .send())
+            .await;
+        // a host that accepts the connection but never answers would otherwise hold up the worker for good
+        let result = match sent {
+            Ok(result) => result,
+            Err(elapsed) if attempt >= options.retries => {
+                return Err(elapsed).with_context(|| format!("No response within {}s", options.timeout.as_secs()));
+            },
+            Err(_) => {
+                attempt += 1;
+                if let Some(progress_bar) = progress_bar {
+                    progress_bar.set_message(format!("{} - retrying ({}/{}) after no response within {}s", display_name, attempt, options.retries, options.timeout.as_secs()));
+                }
+                tokio::time::sleep(retry_delay(attempt)).await;
+                continue;
+            },
+        };
+        // being rate limited says nothing about the file, so it does not use up a retry
+        if let Ok(resp) = &result {
+            options.rate_limiter.record(resp.headers());
+            if RateLimiter::is_rate_limited(resp) && rate_limited_attempt < canvas::MAX_RATE_LIMITED_ATTEMPTS {
+                rate_limited_attempt += 1;
+                let pause = options.rate_limiter.back_off(rate_limited_attempt);
+                if let Some(progress_bar) = progress_bar {
+                    progress_bar.set_message(format!("{} - rate limited by canvas, waiting {}s", display_name, pause.as_secs()));
+                }
+                options.rate_limiter.wait().await;
+                if let Some(progress_bar) = progress_bar {
+                    progress_bar.set_message(display_name.to_string());
+                }
+                continue;
+            }
+        }
+        rate_limited_attempt = 0;
+        let retry_reason = match &result {
+            Ok(resp) if resp.status().is_server_error() || resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS => resp.status().to_string(),
+            Err(e) if e.is_connect() || e.is_timeout() || e.is_request() => e.to_string(),
+            _ => return Ok(result?),
+        };
+        if attempt >= options.retries {
+            return Ok(result?);
+        }
+        attempt += 1;
+
+        // failed attempts still count towards opening the circuit breaker
+        match &result {
+            Ok(resp) => options.circuit_breaker.record_status(resp.url(), resp.status()),
+            Err(e) => if let Some(url) = e.url() {
+                options.circuit_breaker.record_failure(url);
+            },
+        }
+        if let Some(progress_bar) = progress_bar {
+            progress_bar.set_message(format!("{} - retrying ({}/{}) after {}", display_name, attempt, options.retries, retry_reason));
+        }
+        tokio::time::sleep(retry_delay(attempt)).await;
+        if let Some(progress_bar) = progress_bar {
+            progress_bar.set_message(display_name.to_string());
+        }
+    }
+}
+
+// doubles with every attempt, with jitter so workers that failed together do not retry together
+fn retry_delay(attempt: u32) -> std::time::Duration {
+    let delay = 500 * 2u64.pow(attempt.saturating_sub(1).min(10));
+    std::time::Duration::from_millis(delay + rand::thread_rng().gen_range(0..=delay / 2))
+}
+
+// finished files have their modified time set to when they were updated on canvas, so only a file written to
+// after that is picked up, and an older version of the file is not pieced together with the new one
+fn partial_download_size(canvas_file: &canvas::File, download_size: u64) -> Option<u64> {
+    let metadata = std::fs::metadata(long_path(&partial_path(&canvas_file.filepath))).ok()?;
+    // without an updated_at there is no telling which version the partial download is of
+    let modified = metadata.modified().ok()?;
+    let same_version = canvas_file.updated_at.is_some_and(|updated_at| modified > std::time::SystemTime::from(updated_at));
+    (same_version && metadata.len() > 0 && metadata.len() < download_size).then_some(metadata.len())
+}
+
+/// Added to the path of a file while it is downloaded, e.g. `notes.pdf.partial`
+pub const PARTIAL_EXTENSION: &str = "partial";
+
+// files are downloaded next to where they end up, so that only complete files are ever at the real path
+// e.g. notes.pdf is downloaded to notes.pdf.partial
+fn partial_path(filepath: &std::path::Path) -> PathBuf {
+    let mut partial_path = filepath.as_os_str().to_owned();
+    partial_path.push(".");
+    partial_path.push(PARTIAL_EXTENSION);
+    PathBuf::from(partial_path)
+}
+
+// file urls redirect to signed urls on a separate storage host, which must not be handed the canvas token.
+// reqwest already drops the Authorization header when a redirect leaves the host, this covers the requests
+// sent straight to the url a redirect ended at
+fn with_token(options: &DownloadOptions, request: reqwest::RequestBuilder, url: &reqwest::Url) -> reqwest::RequestBuilder {
+    if options.token_urls.iter().any(|token_url| token_url.origin() == url.origin()) {
+        request.bearer_auth(&options.canvas_token)
+    } else {
+        request
+    }
+}
+
+// files smaller than this are not worth splitting into segments
+const SEGMENT_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+// splits the file into byte ranges that are fetched at the same time and written in place
+// gives the bytes written, and the ETag the host sent with the segments
+async fn download_segments(options: &DownloadOptions, url: &reqwest::Url, display_name: &str, partial_path: &std::path::Path, download_size: u64, progress_bar: &ProgressBar) -> Result<(u64, Option<String>)> {
+    let segment_size = download_size.div_ceil(options.segments as u64);
+    let segments = (0..download_size).step_by(segment_size as usize).map(|start| async move {
+        let end = (start + segment_size).min(download_size) - 1;
+        let request = with_token(options, options.client.get(url.clone()), url)
+            .header(header::RANGE, format!("bytes={}-{}", start, end));
+        // a hiccup on one segment is retried like any other download, instead of giving up on the whole file
+        let mut resp = match send_with_retries(options, request, Some(progress_bar), display_name).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                options.circuit_breaker.record_failure(e.downcast_ref::<reqwest::Error>().and_then(|e| e.url()).unwrap_or(url));
+                return Err(e);
+            }
+        };
+        options.circuit_breaker.record_status(resp.url(), resp.status());
+        if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            return Err(anyhow::anyhow!("Expected a partial response for bytes {}-{}, got {}", start, end, resp.status()));
+        }
+
+        let mut file = std::fs::OpenOptions::new().write(true).open(long_path(partial_path))?;
+        file.seek(std::io::SeekFrom::Start(start))?;
+        let mut bytes = 0;
+        while let Some(chunk) = next_chunk(&mut resp, options.timeout).await? {
+            options.speed_limiter.consume(chunk.len()).await;
+            progress_bar.inc(chunk.len() as u64);
+            bytes += chunk.len() as u64;
+            file.write_all(&chunk)?;
+        }
+        if bytes != end - start + 1 {
+            return Err(anyhow::anyhow!("Expected {} bytes for bytes {}-{}, got {}", end - start + 1, start, end, bytes));
+        }
+        Ok((bytes, response_etag(resp.headers())))
+    });
+    let segments = futures::future::try_join_all(segments).await?;
+    let etag = segments.iter().find_map(|(_, etag)| etag.clone());
+    Ok((segments.into_iter().map(|(bytes, _)| bytes).sum(), etag))
+}
+
+async fn finish_download(options: &DownloadOptions, canvas_file: &canvas::File) {
+    options.downloaded_file_ids.lock().await.insert(canvas_file.id);
+    emit(options, Event::Done { id: canvas_file.id });
+    if let Some(queue_file) = &options.queue_file {
+        if let Err(e) = queue_file.mark_done(canvas_file.id) {
+            warn!(file = %canvas_file.display_name, error = ?e, "failed to mark the file as done in the queue file");
+        }
+    }
+}
+
+fn set_modified_time(canvas_file: &canvas::File) -> Result<()> {
+    // the file keeps the time it was written at
+    let updated_at = match canvas_file.updated_at {
+        Some(updated_at) => updated_at,
+        None => return Ok(()),
+    };
+    filetime::set_file_mtime(
+        long_path(&canvas_file.filepath),
+        filetime::FileTime::from_unix_time(
+            updated_at.timestamp(),
+            updated_at.timestamp_subsec_nanos()))?;
+    Ok(())
+}
+
+// counts the file as done on the overall bar, with the bytes downloaded so far
+fn record_overall_progress(overall_progress: &ProgressBar, options: &DownloadOptions) {
+    overall_progress.set_message(HumanBytes(options.downloaded_bytes.load(Ordering::Relaxed)).to_string());
+    overall_progress.inc(1);
+}
+
+/// Downloads the file, keeping track of how it went in the options rather than giving back an error
+pub async fn download_and_record(options: &DownloadOptions, canvas_file: &canvas::File) {
+    let status = match download_file(options, canvas_file).await {
+        Ok(status) => status,
+        // the corrupted copy is already gone, so the next run downloads it again
+        Err(e) if !options.verify && e.is::<ChecksumMismatch>() => {
+            warn!(file = %canvas_file.filepath.display(), "removed the download, {}", e);
+            emit(options, Event::Failed { id: canvas_file.id, error: format!("{:#}", e) });
+            canvas::DownloadStatus::Failed
+        },
+        Err(e) => {
+            emit(options, Event::Failed { id: canvas_file.id, error: format!("{:#}", e) });
+            options.failed_downloads.lock().await.push(canvas::FailedDownload { file: canvas_file.clone(), error: e });
+            canvas::DownloadStatus::Failed
+        }
+    };
+    if let Some(download_records) = &options.download_records {
+        let mut download_record = canvas::DownloadRecord::new(canvas_file, status);
+        if let Some(filepath) = options.renamed_files.lock().await.get(&canvas_file.id) {
+            download_record.filepath = filepath.clone();
+        }
+        download_records.lock().await.push(download_record);
+    }
+}
+
+/// Downloads the file to its `filepath`, unless the copy there is still current
+pub async fn download_file(options: &DownloadOptions, canvas_file: &canvas::File) -> Result<canvas::DownloadStatus> {
+    // there is nothing to fetch for an empty file, so it is created straight away
+    if canvas_file.size == 0 {
+        std::fs::File::create(long_path(&canvas_file.filepath))
+            .with_context(|| format!("Failed to create {}", canvas_file.filepath.to_string_lossy()))?;
+        if set_modified_time(canvas_file).is_err() {
+            warn!(file = %canvas_file.display_name, updated_at = %canvas_file.updated_at.unwrap_or_default(), "failed to set the modified time");
+        }
+        finish_download(options, canvas_file).await;
+        options.empty_downloads.fetch_add(1, Ordering::Relaxed);
+        return Ok(canvas::DownloadStatus::Downloaded);
+    }
+
+    // We need to determine the file size before we download, so we can create a ProgressBar
+    // A Header request for the CONTENT_LENGTH header gets us the file size
+    // The HEAD request also follows redirects, which tells us the host actually serving the file
+    // and with the ETag from the last download, whether the copy on disk is still current
+    let (download_size, resolved_url, accepts_ranges, extension, known_size) = {
+        let url = reqwest::Url::parse(&canvas_file.url)
+            .with_context(|| format!("Invalid url {}", canvas_file.url))?;
+        if !options.circuit_breaker.allow(&url) {
+            anyhow::bail!("{} is not responding", url.host_str().unwrap_or_default());
+        }
+        let mut request = with_token(options, options.client.head(url.clone()), &url).timeout(options.timeout);
+        let stored_etag = options.etags.lock().unwrap().get(&canvas_file.id).cloned();
+        // a copy that is missing or cut short has to be downloaded again, whatever the host says about it
+        let intact = std::fs::metadata(long_path(&canvas_file.filepath)).is_ok_and(|metadata| metadata.len() == canvas_file.size);
+        if let Some(stored_etag) = stored_etag.filter(|_| options.send_etags && intact) {
+            request = request.header(header::IF_NONE_MATCH, stored_etag);
+        }
+        let resp = match send_with_retries(options, request, None, &canvas_file.display_name).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                options.circuit_breaker.record_failure(e.downcast_ref::<reqwest::Error>().and_then(|e| e.url()).unwrap_or(&url));
+                return Err(e);
+            }
+        };
+        options.circuit_breaker.record_status(resp.url(), resp.status());
+        if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+            debug!(file = %canvas_file.filepath.display(), "not modified since the last download");
+            // the copy on disk is what canvas has now, so it should not come up as updated again
+            if set_modified_time(canvas_file).is_err() {
+                warn!(file = %canvas_file.display_name, updated_at = %canvas_file.updated_at.unwrap_or_default(), "failed to set the modified time");
+            }
+            options.not_modified_file_ids.lock().await.insert(canvas_file.id);
+            emit(options, Event::NotModified { id: canvas_file.id });
+            if let Some(queue_file) = &options.queue_file {
+                if let Err(e) = queue_file.mark_done(canvas_file.id) {
+                    warn!(file = %canvas_file.display_name, error = ?e, "failed to mark the file as done in the queue file");
+                }
+            }
+            return Ok(canvas::DownloadStatus::NotModified);
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("{} responded with {}", resp.url(), resp.status());
+        }
+        let content_length = resp.headers() // Gives us the HeaderMap
+            .get(header::CONTENT_LENGTH) // Gives us an Option containing the HeaderValue
+            .and_then(|ct_len| ct_len.to_str().ok()) // Unwraps the Option as &str
+            .and_then(|ct_len| ct_len.parse().ok()); // Parses the Option as u64
+        let download_size = content_length.unwrap_or(canvas_file.size); // Fallback to the size canvas reports
+        let accepts_ranges = resp.headers()
+            .get(header::ACCEPT_RANGES)
+            .is_some_and(|accept_ranges| accept_ranges.as_bytes() == b"bytes");
+        (download_size, resp.url().clone(), accepts_ranges, content_disposition_extension(resp.headers()), content_length.is_some())
+    };
+
+    // files uploaded with a generic name still get a usable extension, the name itself is left as it is
+    let renamed_file;
+    let canvas_file = match extension {
+        Some(extension) if canvas_file.filepath.extension().is_none() => {
+            renamed_file = canvas::File {
+                filepath: canvas_file.filepath.with_extension(extension),
+                ..canvas_file.clone()
+            };
+            debug!(path = %renamed_file.filepath.display(), "added the extension from the Content-Disposition");
+            options.renamed_files.lock().await.insert(renamed_file.id, renamed_file.filepath.clone());
+            &renamed_file
+        },
+        _ => canvas_file,
+    };
+
+    let progress_bar = options.progress_bars.add(ProgressBar::new(download_size));
+
+    let mut style_template = "[{bar:20.cyan/blue}] {bytes}/{total_bytes} - {bytes_per_sec}, {eta} left - {msg}";
+    if let Some(size) = termsize::get() {
+        // arbitrary 100
+        if size.cols < 100 {
+            style_template = "[{wide_bar:.cyan/blue}] {total_bytes} - {msg}";
+        }
+    }
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template(style_template).unwrap()
+            .progress_chars("=>-")
+    );
+
+    let message = canvas_file.display_name.to_string();
+
+    progress_bar.set_message(message);
+
+    // the progress bar is hidden with --output-format json, but still keeps count of the bytes
+    let progress_events = options.on_event.clone().map(|on_event| {
+        let progress_bar = progress_bar.clone();
+        let id = canvas_file.id;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_millis(500));
+            // the first tick is right away, before anything has arrived
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                on_event(&Event::Progress { id, bytes: progress_bar.position(), total: progress_bar.length().unwrap_or(download_size) });
+            }
+        })
+    });
+    let downloaded = download_to_file(options, canvas_file, &progress_bar, download_size, known_size, &resolved_url, accepts_ranges).await;
+    if let Some(progress_events) = progress_events {
+        progress_events.abort();
+    }
+
+    match downloaded {
+        Ok(etag) => {
+            progress_bar.finish();
+            let mut etags = options.etags.lock().unwrap();
+            match etag {
+                Some(etag) => etags.insert(canvas_file.id, etag),
+                None => etags.remove(&canvas_file.id),
+            };
+            Ok(canvas::DownloadStatus::Downloaded)
+        },
+        Err(e) => {
+            progress_bar.abandon_with_message(format!("{} - failed: {}", canvas_file.display_name, e));
+            Err(e)
+        },
+    }
+}
+
+fn response_etag(headers: &header::HeaderMap) -> Option<String> {
+    headers.get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string)
+}
+
+// e.g. pdf from attachment; filename="lecture.pdf", or from filename*=UTF-8''lecture.pdf
+fn content_disposition_extension(headers: &header::HeaderMap) -> Option<String> {
+    let content_disposition = headers.get(header::CONTENT_DISPOSITION)?.to_str().ok()?;
+    let filename = content_disposition.split(';')
+        .map(str::trim)
+        .find_map(|param| param.strip_prefix("filename*=")
+            .and_then(|filename| filename.split_once("''"))
+            .map(|(_, filename)| filename)
+            .or_else(|| param.strip_prefix("filename=")))?
+        .trim_matches('"');
+    let extension = std::path::Path::new(filename).extension()?.to_str()?;
+    // the extension becomes part of the path, so anything unusual is left out
+    (!extension.is_empty() && extension.chars().all(|c| c.is_ascii_alphanumeric())).then(|| extension.to_string())
+}
+
+// `known_size` is whether download_size is the length the host gave, rather than the size canvas has for the file
+// gives the ETag of the download, which is kept for the next run
+async fn download_to_file(options: &DownloadOptions, canvas_file: &canvas::File, progress_bar: &ProgressBar, download_size: u64, known_size: bool, resolved_url: &reqwest::Url, accepts_ranges: bool) -> Result<Option<String>> {
+    let partial_path = partial_path(&canvas_file.filepath);
+    let mut resume_from = if options.resume { partial_download_size(canvas_file, download_size) } else { None };
+    let mut file = match resume_from {
+        Some(_) => std::fs::OpenOptions::new().append(true).open(long_path(&partial_path)),
+        None => std::fs::File::create(long_path(&partial_path)),
+    }.with_context(|| format!("Failed to create {}", partial_path.to_string_lossy()))?;
+
+    // held until the file has finished streaming
+    let _host_permit = options.host_limiter.acquire(resolved_url).await;
+
+    let started_at = chrono::Utc::now();
+    let start = std::time::Instant::now();
+
+    if !options.circuit_breaker.allow(resolved_url) {
+        anyhow::bail!("{} is not responding", resolved_url.host_str().unwrap_or_default());
+    }
+    let segmented = resume_from.is_none() && options.segments > 1 && accepts_ranges && download_size >= SEGMENT_MIN_SIZE;
+    let segmented_bytes = if segmented {
+        match download_segments(options, resolved_url, &canvas_file.display_name, &partial_path, download_size, progress_bar).await {
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                warn!(file = %canvas_file.display_name, error = ?e, "failed to download the file in segments, downloading it over one connection instead");
+                progress_bar.set_position(0);
+                file.set_len(0)?;
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut hasher = FileHasher::new(options, canvas_file);
+    let mut etag;
+    let bytes = if let Some((segmented_bytes, segments_etag)) = segmented_bytes {
+        etag = segments_etag;
+        // the segments arrive out of order, so the checksum is taken from the finished file
+        if !hasher.is_empty() {
+            std::io::copy(&mut std::fs::File::open(long_path(&partial_path))?, &mut hasher)?;
+        }
+        segmented_bytes
+    } else {
+        let mut attempt = 0;
+        // the same url the HEAD request ended up at, so the size and the content come from the same place
+        let mut url = resolved_url.clone();
+        let mut re_resolved = false;
+        loop {
+            let mut request = with_token(options, options.client.get(url.clone()), &url);
+            if let Some(resume_from) = resume_from {
+                request = request.header(header::RANGE, format!("bytes={}-", resume_from));
+            }
+            let mut file_response = match send_with_retries(options, request, Some(progress_bar), &canvas_file.display_name).await {
+                Ok(file_response) => file_response,
+                Err(e) => {
+                    options.circuit_breaker.record_failure(e.downcast_ref::<reqwest::Error>().and_then(|e| e.url()).unwrap_or(&url));
+                    return Err(e);
+                }
+            };
+            options.circuit_breaker.record_status(file_response.url(), file_response.status());
+            // signed urls expire, e.g. while a retry waits, so canvas is asked for a new one by following the file url again
+            let expired = matches!(file_response.status(), reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN);
+            if expired && !re_resolved && url.as_str() != canvas_file.url {
+                debug!(file = %canvas_file.filepath.display(), status = %file_response.status(), "resolving the download url again");
+                url = reqwest::Url::parse(&canvas_file.url)
+                    .with_context(|| format!("Invalid url {}", canvas_file.url))?;
+                re_resolved = true;
+                continue;
+            }
+            if !file_response.status().is_success() {
+                anyhow::bail!("{} responded with {}", file_response.url(), file_response.status());
+            }
+            // a retry goes to the url that worked, and may ask for a new one again once that expires
+            url = file_response.url().clone();
+            re_resolved = false;
+            // the host serving the content after the redirects is the one that tags it
+            etag = response_etag(file_response.headers());
+            match resume_from {
+                Some(resume_from) if file_response.status() == reqwest::StatusCode::PARTIAL_CONTENT => {
+                    progress_bar.set_position(resume_from);
+                    if !hasher.is_empty() {
+                        std::io::copy(&mut std::fs::File::open(long_path(&partial_path))?, &mut hasher)?;
+                    }
+                },
+                // the host sent the whole file instead
+                Some(_) => file.set_len(0)?,
+                None => {},
+            }
+
+            match stream_to_file(options, &mut file_response, &mut file, progress_bar, &mut hasher).await {
+                Ok(streamed_bytes) => break streamed_bytes,
+                // only a dropped or stalled connection is worth another try, not a full disk
+                Err(e) if attempt < options.retries && (e.is::<reqwest::Error>() || e.is::<tokio::time::error::Elapsed>()) => {
+                    attempt += 1;
+                    progress_bar.set_message(format!("{} - retrying ({}/{}) after {}", canvas_file.display_name, attempt, options.retries, e));
+                    // the download starts over, so the partial file is thrown away
+                    resume_from = None;
+                    progress_bar.set_position(0);
+                    file.set_len(0)?;
+                    file.rewind()?;
+                    hasher = FileHasher::new(options, canvas_file);
+                    tokio::time::sleep(retry_delay(attempt)).await;
+                    progress_bar.set_message(canvas_file.display_name.to_string());
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    };
+    options.downloaded_bytes.fetch_add(bytes, Ordering::Relaxed);
+    // canvas also provides a modified_time of the file but updated_at should be more proper
+    // as it probably represents the upload date of the file which is more apt for determining
+    // if the file was changed since downloading it
+    // this is only done once the file is complete, as writing to the file changes its modified time
+    let downloaded_size = file.metadata()?.len();
+    drop(file);
+    // a download that ended early is kept as it is, so the next run can pick up where it stopped
+    if known_size && downloaded_size != download_size {
+        anyhow::bail!("Expected {} bytes, got {}", download_size, downloaded_size);
+    }
+    if let (Some(expected), Some(md5)) = (&canvas_file.md5, hasher.md5.take()) {
+        let actual = format!("{:x}", md5.finalize());
+        if !actual.eq_ignore_ascii_case(expected) {
+            // it would be resumed from otherwise
+            std::fs::remove_file(long_path(&partial_path))
+                .with_context(|| format!("Failed to remove {}", partial_path.to_string_lossy()))?;
+            return Err(ChecksumMismatch { expected: expected.clone(), actual }.into());
+        }
+    }
+    std::fs::rename(long_path(&partial_path), long_path(&canvas_file.filepath))
+        .with_context(|| format!("Failed to move {} to {}", partial_path.to_string_lossy(), canvas_file.filepath.to_string_lossy()))?;
+    if set_modified_time(canvas_file).is_err() {
+        warn!(file = %canvas_file.display_name, updated_at = %canvas_file.updated_at.unwrap_or_default(), "failed to set the modified time");
+    }
+    finish_download(options, canvas_file).await;
+    if let (Some(checksums), Some(sha256)) = (&options.checksums, hasher.sha256) {
+        checksums.lock().await.insert(canvas_file.filepath.clone(), format!("{:x}", sha256.finalize()));
+    }
+
+    if let Some(timings) = &options.timings {
+        timings.lock().await.push(canvas::FileTiming {
+            id: canvas_file.id,
+            filepath: canvas_file.filepath.clone(),
+            host: resolved_url.host_str().unwrap_or_default().to_string(),
+            bytes,
+            started_at,
+            finished_at: chrono::Utc::now(),
+            duration: start.elapsed(),
+        });
+    }
+    Ok(etag)
+}
+
+// windows refuses paths longer than MAX_PATH, unless they are given as extended-length paths
+#[cfg(windows)]
+pub(crate) fn long_path(path: &std::path::Path) -> std::borrow::Cow<'_, std::path::Path> {
+    // directories are limited to 248 characters, to leave room for an 8.3 file name
+    const MAX_DIRECTORY_PATH: usize = 248;
+    let path_string = path.to_string_lossy();
+    if path_string.len() < MAX_DIRECTORY_PATH || path_string.starts_with(r"\\?\") {
+        return std::borrow::Cow::Borrowed(path);
+    }
+    // extended-length paths are not normalized by windows, so they have to be absolute with no . or ..
+    match std::path::absolute(path) {
+        Ok(absolute_path) => {
+            let absolute_path = absolute_path.to_string_lossy();
+            std::borrow::Cow::Owned(PathBuf::from(match absolute_path.strip_prefix(r"\\") {
+                Some(unc_path) => format!(r"\\?\UNC\{}", unc_path),
+                None => format!(r"\\?\{}", absolute_path),
+            }))
+        },
+        Err(_) => std::borrow::Cow::Borrowed(path),
+    }
+}
+
+#[cfg(not(windows))]
+pub(crate) fn long_path(path: &std::path::Path) -> std::borrow::Cow<'_, std::path::Path> {
+    std::borrow::Cow::Borrowed(path)
+}
@@ -0,0 +1,10 @@
+//! Talks to the canvas api, so other tools can list and download course files without going through the command line.
+
+pub mod canvas;
+mod client;
+pub mod download;
+mod provider;
+pub mod traverse;
+
+pub use client::CanvasClient;
+pub use provider::{collect_course, ContentProvider, CourseContext, DownloadItem};
@@ -1,429 +1,2046 @@
 use anyhow::{Context, Result};
-use canvas::ProcessOptions;
-use chrono::DateTime;
-use clap::Parser;
-use futures::{future::BoxFuture, FutureExt};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::header;
-use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}, path::PathBuf};
+use canvas_downloader::{canvas::{self, CircuitBreaker, DownloadOptions, Event, EventHandler, FileQueue, HostLimiter, ProcessOptions, RateLimiter, SpeedLimiter}, download::{self, PARTIAL_EXTENSION}, traverse::{self, canvas_ignored, dedupe_files, process_folder, traversal_options, truncate_name, walk_folders}, collect_course, CanvasClient, ContentProvider, CourseContext};
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Utc};
+use clap::{CommandFactory, ErrorKind, FromArgMatches, Parser, ValueSource};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use providers::{AnnouncementsProvider, DiscussionsProvider, FilesProvider, FrontPageProvider, MediaProvider, MediaQuality, ModulesProvider, PagesProvider, SyllabusProvider};
+use sha2::{Digest, Sha256};
+use std::{collections::{BTreeMap, HashMap, HashSet}, io::{IsTerminal, Write}, sync::{Arc, atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}}, path::PathBuf};
 use tokio::sync::Mutex;
+use tracing_subscriber::{filter::{LevelFilter, Targets}, fmt::MakeWriter, layer::SubscriberExt, util::SubscriberInitExt};
+
+// messages for people go to stderr with --output-format json, so stdout only holds the events
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !crate::quiet() {
+            if crate::json_output() {
+                eprintln!($($arg)*)
+            } else {
+                println!($($arg)*)
+            }
+        }
+    };
+}
+
+// problems are still shown with --quiet, on stderr like with --output-format json
+macro_rules! warning {
+    () => {
+        warning!("")
+    };
+    ($($arg:tt)*) => {{
+        // error messages can hold the urls of requests, and with them anything passed in the url
+        let message = crate::redact(&format!($($arg)*));
+        if crate::quiet() || crate::json_output() {
+            eprintln!("{}", message)
+        } else {
+            println!("{}", message)
+        }
+    }};
+}
+
+mod config;
+mod providers;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = CommandLineOptions::parse();
+async fn main() -> std::process::ExitCode {
+    let top_matches = cli().get_matches();
+    // without a subcommand the files are synced, like before there were subcommands
+    let (subcommand, matches) = match top_matches.subcommand() {
+        Some((subcommand, matches)) => (subcommand, matches.clone()),
+        None => ("sync", top_matches.clone()),
+    };
+    // only the global options are read from before the subcommand
+    if top_matches.subcommand().is_some() {
+        let misplaced = CommandLineOptions::command().get_arguments()
+            .map(|arg| arg.get_id())
+            .filter(|id| !matches!(*id, "help" | "version") && !GLOBAL_ARGS.contains(id))
+            .find(|id| top_matches.value_source(id) == Some(ValueSource::CommandLine));
+        if let Some(id) = misplaced {
+            cli().error(ErrorKind::ArgumentConflict, format!("--{} has to come after the {} subcommand", id, subcommand)).exit();
+        }
+    }
+    let mut args = CommandLineOptions::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match subcommand {
+        "list" => args.list = true,
+        "login" => {
+            args.login = true;
+            args.save_credentials = true;
+        },
+        _ => {},
+    }
+    JSON_OUTPUT.store(matches!(args.output_format, OutputFormat::Json), Ordering::Relaxed);
+    QUIET.store(args.quiet, Ordering::Relaxed);
+    if let Some(shell) = args.generate_completions {
+        clap_complete::generate(shell, &mut cli(), env!("CARGO_PKG_NAME"), &mut std::io::stdout());
+        return std::process::ExitCode::SUCCESS;
+    }
+    if matches.value_source("canvas-token") == Some(ValueSource::CommandLine) {
+        eprintln!("Warning: the canvas token passed with -t is now in the shell history, and can be seen by other users in the list of processes");
+        eprintln!("Pass it with --token-file, --token-stdin or CANVAS_TOKEN instead, or save it once with `login -c <PATH> --use-keyring`");
+    }
+    if let Some(canvas_token) = &args.canvas_token {
+        add_secret(canvas_token);
+    }
+    if args.login && args.canvas_credential_path.is_none() {
+        cli().error(ErrorKind::MissingRequiredArgument, "login needs -c <PATH> to save the credentials to").exit();
+    }
+    // clap would also count a token from the environment as conflicting, so only an explicit -t is rejected
+    if matches.value_source("canvas-token") == Some(ValueSource::CommandLine) && (args.token_file.is_some() || args.token_stdin) {
+        CommandLineOptions::command()
+            .error(ErrorKind::ArgumentConflict, "--canvas-token cannot be used with --token-file or --token-stdin")
+            .exit();
+    }
+    // the environment is only a fallback, values in the credential file take precedence over it
+    if args.canvas_credential_path.is_some() && !args.save_credentials {
+        if matches.value_source("canvas-url") == Some(ValueSource::EnvVariable) {
+            args.canvas_url = None;
+        }
+        if matches.value_source("canvas-token") == Some(ValueSource::EnvVariable) {
+            args.canvas_token = None;
+        }
+    }
+    // values from the config file only fill in what was not passed on the command line
+    let result = match config::load_config() {
+        Ok(config) => {
+            if let Some(config) = config {
+                config.merge_into(&mut args, &matches);
+            }
+            run(args).await
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", redact(&format!("{:?}", e)));
+            std::process::ExitCode::from(exit_status(&e) as u8)
+        }
+    }
+}
 
-    if (args.canvas_url.is_none() || args.canvas_token.is_none()) && args.canvas_credential_path.is_none() {
-        panic!("Provide canvas url and token via -u and -t respectively or via a credential file -c");
+// how to reach canvas and what to print, these can go before or after the subcommand
+const GLOBAL_ARGS: &[&str] = &["canvas-url", "api-url", "canvas-token", "token-file", "token-stdin", "canvas-credential-path", "proxy", "timeout", "quiet", "verbose"];
+
+// every subcommand takes the same options, which are read into CommandLineOptions from the subcommand's matches
+// the same way as without a subcommand, `login` only hides the ones that have nothing to do with it
+fn cli() -> clap::Command<'static> {
+    let mut command = CommandLineOptions::command();
+    for id in GLOBAL_ARGS {
+        command = command.mut_arg(*id, |arg| arg.global(true));
+    }
+    let args: Vec<clap::Arg<'static>> = command.get_arguments()
+        .filter(|arg| !matches!(arg.get_id(), "help" | "version") && !GLOBAL_ARGS.contains(&arg.get_id()))
+        .cloned()
+        .collect();
+    let login_args = args.iter().cloned().map(|arg| match arg.get_id() {
+        // login always saves, so --use-keyring does not need -s with it
+        "use-keyring" => clap::Arg::new("use-keyring").long("use-keyring").takes_value(false)
+            .help("Save the canvas token to the system keyring instead of the credential file"),
+        _ => arg.hide(true),
+    });
+    command
+        .subcommand(clap::Command::new("sync")
+            .about("Download the files of the courses, which is also what happens without a subcommand")
+            .args(args.iter().cloned()))
+        .subcommand(clap::Command::new("list")
+            .about("Print the courses, folders and files on canvas as a tree, without downloading anything")
+            .args(args.iter().cloned().map(|arg| if arg.get_id() == "list" { arg.hide(true) } else { arg })))
+        .subcommand(clap::Command::new("login")
+            .about("Check the canvas url and token, and save them to the credential file given with -c")
+            .args(login_args))
+}
+
+// the tokens of every profile in the run, which are left out of anything printed
+static SECRETS: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+// anything shorter would also match ordinary words
+const MIN_SECRET_LENGTH: usize = 8;
+
+fn add_secret(secret: &str) {
+    let secret = secret.trim();
+    let mut secrets = SECRETS.lock().unwrap();
+    if secret.len() >= MIN_SECRET_LENGTH && !secrets.iter().any(|known| known == secret) {
+        secrets.push(secret.to_string());
     }
+}
 
-    if !args.destination_folder.exists() {
-        std::fs::create_dir(&args.destination_folder)
-            .with_context(|| format!("Failed to create directory: {}", args.destination_folder.to_string_lossy()))?;
+fn redact(text: &str) -> String {
+    let secrets = SECRETS.lock().unwrap();
+    let mut text = text.to_string();
+    for secret in secrets.iter() {
+        text = text.replace(secret.as_str(), "[REDACTED]");
     }
+    text
+}
 
-    let credentials: Option<canvas::Credentials> = if args.canvas_credential_path.is_some() {
-        let path = args.canvas_credential_path.clone().unwrap();
+fn exit_status(e: &anyhow::Error) -> ExitStatus {
+    // downcasting finds the status even when more context was added on top of it
+    e.downcast_ref::<ExitStatus>()
+        .copied()
+        .or_else(|| e.chain().any(|cause| cause.is::<reqwest::Error>()).then_some(ExitStatus::Network))
+        .unwrap_or(ExitStatus::Generic)
+}
 
-        if !path.exists() {
-            if !args.save_credentials {
-                panic!("The given path to the credentials file does not exists");
-            } else {
-                Option::None
+async fn run(mut args: CommandLineOptions) -> Result<()> {
+
+    // the shell does not always expand these, e.g. when the path comes from a quoted argument
+    args.destination_folder = expand_path(&args.destination_folder)?;
+    for path in [&mut args.canvas_credential_path, &mut args.token_file, &mut args.seen_index, &mut args.state_file, &mut args.timings, &mut args.course_map, &mut args.queue_file, &mut args.manifest, &mut args.dump_metadata].into_iter().flatten() {
+        *path = expand_path(path)?;
+    }
+
+    // the events take the place of the progress bars, and --quiet has neither
+    let progress_bars = Arc::new(if json_output() || quiet() || args.no_progress {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    });
+    init_logging(args.verbose, progress_bars.clone());
+
+    // reading the token from a file or stdin keeps it out of the process list and shell history
+    if let Some(path) = &args.token_file {
+        let token = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read token file: {}", path.to_string_lossy()))?;
+        args.canvas_token = Some(token.trim_end().to_string());
+    } else if args.token_stdin {
+        let mut token = String::new();
+        std::io::stdin().read_line(&mut token).context("Failed to read token from stdin")?;
+        args.canvas_token = Some(token.trim_end().to_string());
+    }
+
+    // a credential file with profiles holds several canvas instances, each is downloaded on its own
+    if let Some(path) = args.canvas_credential_path.as_ref().filter(|_| !args.save_credentials) {
+        if let canvas::CredentialFile::Profiles(profiles) = load_credential_file(path)? {
+            let profiles: Vec<canvas::Profile> = match &args.profile {
+                Some(name) => {
+                    let profile = profiles.into_iter().find(|profile| profile.name == *name)
+                        .with_context(|| format!("There is no profile named {} in the credential file: {}", name, path.to_string_lossy()))
+                        .context(ExitStatus::BadArguments)?;
+                    vec![profile]
+                },
+                None => profiles,
+            };
+            let all_profiles = args.profile.is_none();
+            if all_profiles && !(args.dry_run || args.diff || args.list) && !args.destination_folder.exists() {
+                std::fs::create_dir_all(&args.destination_folder)
+                    .with_context(|| format!("Failed to create directory: {}", args.destination_folder.to_string_lossy()))
+                    .context(ExitStatus::Destination)?;
             }
-        } else {
-            if !args.save_credentials {
-                let file = std::fs::File::open(path)?;
-                serde_json::from_reader(file).expect("Credential file is not valid json")
-            } else {
-                Option::None
+            for profile in profiles {
+                let mut profile_args = args.clone();
+                profile_args.canvas_credential_path = None;
+                profile_args.canvas_url.get_or_insert_with(|| profile.credentials.canvas_url.clone());
+                if profile_args.api_url.is_none() {
+                    profile_args.api_url = profile.credentials.api_url.clone();
+                }
+                if profile_args.canvas_token.is_none() {
+                    profile_args.canvas_token = Some(match profile.credentials.canvas_token {
+                        Some(canvas_token) => canvas_token,
+                        None => keyring_entry(&profile.credentials.canvas_url)
+                            .and_then(|entry| entry.get_password())
+                            .with_context(|| format!("Failed to read the canvas token for {} from the system keyring, pass it with -t instead", profile.credentials.canvas_url))
+                            .context(ExitStatus::BadArguments)?,
+                    });
+                }
+                // every profile gets a folder of its own, so courses from different instances do not mix
+                if all_profiles {
+                    status!("Profile {}", profile.name);
+                    profile_args.destination_folder = args.destination_folder.join(sanitize_filename::sanitize(&profile.name));
+                }
+                download_courses(profile_args, &progress_bars).await?;
             }
+            return Ok(());
+        } else if args.profile.is_some() {
+            return Err(anyhow::anyhow!("--profile needs a credential file with profiles: {}", path.to_string_lossy())
+                .context(ExitStatus::BadArguments));
         }
-    } else {
-        Option::None
-    };
+    }
 
-    let canvas_url = if args.canvas_url.is_some() {
-        args.canvas_url.unwrap()
-    } else {
-        credentials.clone().unwrap().canvas_url
+    download_courses(args, &progress_bars).await
+}
+
+async fn download_courses(mut args: CommandLineOptions, progress_bars: &Arc<MultiProgress>) -> Result<()> {
+    // each profile has a destination folder, and so a seen index, of its own
+    if args.only_new {
+        args.seen_index = Some(args.destination_folder.join(SEEN_INDEX_FILE));
+    }
+    // a pasted course url gives us both the canvas instance and the course to download
+    let course_urls = args.course_url.iter()
+        .map(|course_url| parse_course_url(course_url))
+        .collect::<Result<Vec<_>>>()
+        .context(ExitStatus::BadArguments)?;
+    for (canvas_url, _) in &course_urls {
+        match &args.canvas_url {
+            Some(existing_url) if existing_url.trim_end_matches('/') != canvas_url => {
+                return Err(anyhow::anyhow!("The course url {} does not belong to the canvas url {}", canvas_url, existing_url)
+                    .context(ExitStatus::BadArguments));
+            },
+            Some(_) => {},
+            None => args.canvas_url = Some(canvas_url.clone()),
+        }
+    }
+    let course_url_ids = course_urls.iter().map(|(_, course_id)| *course_id).collect::<HashSet<_>>();
+
+    if (args.canvas_url.is_none() || args.canvas_token.is_none()) && args.canvas_credential_path.is_none() {
+        return Err(anyhow::anyhow!("Provide canvas url and token via -u and -t respectively, via a credential file -c or via the CANVAS_URL and CANVAS_TOKEN environment variables")
+            .context(ExitStatus::BadArguments));
+    }
+
+    // files updated while this run is going are picked up by the next --since last
+    let run_started_at = Utc::now();
+    let record_last_sync = args.since.is_some();
+    let since = match args.since {
+        Some(Since::Date(date)) => Some(start_of_day(date)?),
+        Some(Since::Last) => {
+            let last_sync = load_last_sync(&args.destination_folder)?;
+            if last_sync.is_none() {
+                status!("No previous sync found in {}, downloading every file", args.destination_folder.to_string_lossy());
+            }
+            last_sync
+        },
+        None => None,
     };
 
-    let canvas_token = if args.canvas_token.is_some() {
-        args.canvas_token.unwrap()
+    let include_globs = build_glob_set(&args.include).context(ExitStatus::BadArguments)?;
+    let exclude_globs = build_glob_set(&args.exclude).context(ExitStatus::BadArguments)?;
+
+    // previews leave the destination folder untouched
+    let dry_run = args.dry_run || args.diff || args.list || args.login;
+    if !dry_run && !args.destination_folder.exists() {
+        std::fs::create_dir_all(&args.destination_folder)
+            .with_context(|| format!("Failed to create directory: {}", args.destination_folder.to_string_lossy()))
+            .context(ExitStatus::Destination)?;
+    }
+
+    // a bag keeps the downloaded files under data/, next to the bagit metadata files
+    let payload_folder = if args.bagit {
+        args.destination_folder.join("data")
     } else {
-        credentials.clone().unwrap().canvas_token
+        args.destination_folder.clone()
     };
+    if !dry_run && !payload_folder.exists() {
+        std::fs::create_dir_all(&payload_folder)
+            .with_context(|| format!("Failed to create directory: {}", payload_folder.to_string_lossy()))
+            .context(ExitStatus::Destination)?;
+    }
+    // otherwise every download would fail one by one, after all the courses have been looked through
+    if !dry_run {
+        check_writable(&payload_folder).context(ExitStatus::Destination)?;
+    }
 
-    if args.save_credentials {
-        if args.canvas_credential_path.is_none() {
-            panic!("Provide the destination path to save the credential to");
+    let course_map = match &args.course_map {
+        Some(path) => load_course_map(path).context(ExitStatus::BadArguments)?,
+        None => HashMap::new(),
+    };
+    if !dry_run {
+        for (course_id, course_folder_path) in &course_map {
+            std::fs::create_dir_all(course_folder_path)
+                .with_context(|| format!("Failed to create directory for course {}: {}", course_id, course_folder_path.to_string_lossy()))
+                .context(ExitStatus::Destination)?;
         }
+    }
+
+    // when saving, the credential file is written to rather than read from
+    let credentials: Option<canvas::Credentials> = match &args.canvas_credential_path {
+        Some(path) if !args.save_credentials => {
+            if !path.exists() {
+                return Err(anyhow::anyhow!("The given path to the credentials file does not exist: {}", path.to_string_lossy())
+                    .context(ExitStatus::BadArguments));
+            }
+            match load_credential_file(path)? {
+                canvas::CredentialFile::Single(credentials) => Some(credentials),
+                // profiles are picked before getting here
+                canvas::CredentialFile::Profiles(_) => None,
+            }
+        },
+        _ => None,
+    };
+
+    // some institutions log in at one domain but serve the api from another
+    // canvas_url is the one shown to the user, api_url is the one requests are sent to
+    let (canvas_url, api_url) = match (args.canvas_url, &credentials) {
+        (Some(canvas_url), _) => (canvas_url.clone(), args.api_url.unwrap_or(canvas_url)),
+        (None, Some(credentials)) => {
+            let api_url = args.api_url
+                .or_else(|| credentials.api_url.clone())
+                .unwrap_or_else(|| credentials.canvas_url.clone());
+            (credentials.canvas_url.clone(), api_url)
+        },
+        (None, None) => {
+            return Err(anyhow::anyhow!("Provide canvas url via -u to save it to the credential file")
+                .context(ExitStatus::BadArguments));
+        },
+    };
+
+    let canvas_token = match (args.canvas_token, &credentials) {
+        (Some(canvas_token), _) => canvas_token,
+        (None, Some(credentials)) => match &credentials.canvas_token {
+            Some(canvas_token) => canvas_token.clone(),
+            None => keyring_entry(&canvas_url)
+                .and_then(|entry| entry.get_password())
+                .with_context(|| format!("Failed to read the canvas token for {} from the system keyring, pass it with -t instead", canvas_url))
+                .context(ExitStatus::BadArguments)?,
+        },
+        (None, None) => {
+            return Err(anyhow::anyhow!("Provide canvas token via -t to save it to the credential file")
+                .context(ExitStatus::BadArguments));
+        },
+    };
+    // the token can also come from a file, stdin, the credential file or the keyring
+    add_secret(&canvas_token);
+
+    let courses_link = format!("{}/api/v1/courses", api_url);
+
+    let timeout = std::time::Duration::from_secs(args.timeout.get());
+    // api responses are small, so they get the timeout as a whole, while downloads only time out when they stall
+    let api_client = build_client(timeout, args.proxy.as_ref()).timeout(timeout).build()?;
+    let client = build_client(timeout, args.proxy.as_ref()).build()?;
+    let mut canvas_client = CanvasClient::new(api_client, api_url.clone(), canvas_token.clone())
+        .with_per_page(args.per_page.get());
+    if let Some(requests_per_second) = args.requests_per_second {
+        canvas_client = canvas_client.with_rate_limiter(RateLimiter::with_requests_per_second(requests_per_second));
+    }
+
+    // also serves as a check that the token is valid before doing any real work
+    let user = fetch_user(&canvas_client).await?;
+    // stdout only contains the json when listing courses
+    if !args.courses_json {
+        status!("Logged in to {} as {} (id: {})", canvas_url, user.name, user.id);
+    }
 
-        let path = args.canvas_credential_path.clone().unwrap();
-        let file = std::fs::File::create(path)?;
+    // only credentials that work are saved
+    if let (true, Some(path)) = (args.save_credentials, &args.canvas_credential_path) {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("Failed to create credential file: {}", path.to_string_lossy()))?;
+        // only the token is a secret, the rest of the credentials stay in the file
+        let keyring_saved = args.use_keyring && match keyring_entry(&canvas_url).and_then(|entry| entry.set_password(&canvas_token)) {
+            Ok(()) => true,
+            Err(e) => {
+                warning!("Failed to save the canvas token to the system keyring, saving it to the credential file instead\n{}", e);
+                false
+            }
+        };
         let credentials = canvas::Credentials {
             canvas_url: canvas_url.clone(),
-            canvas_token: canvas_token.clone(),
+            api_url: (api_url != canvas_url).then(|| api_url.clone()),
+            canvas_token: (!keyring_saved).then(|| canvas_token.clone()),
         };
         serde_json::to_writer_pretty(file, &credentials)?;
+        if args.login {
+            status!("Saved the credentials to {}", path.to_string_lossy());
+            return Ok(());
+        }
     }
 
-    let courses_link = format!("{}/api/v1/courses", canvas_url);
+    // resuming picks up the queue of the aborted run instead of scanning the courses again
+    let resumed_queue = match &args.queue_file {
+        Some(path) if args.resume => {
+            if !path.exists() {
+                return Err(anyhow::anyhow!("No queue to resume at {}", path.to_string_lossy()))
+                    .context(ExitStatus::BadArguments);
+            }
+            Some(load_queue_file(path)?)
+        },
+        _ => None,
+    };
 
-    let client = reqwest::Client::new();
+    // a single folder is walked on its own, without looking at the courses
+    let courses = if resumed_queue.is_some() || args.folder_id.is_some() {
+        Vec::new()
+    } else {
+        // most only care about the current term, but courses picked by id or url may well be concluded
+        let include_concluded = args.include_concluded || !args.course_id.is_empty() || !course_url_ids.is_empty();
+        let courses = if args.include_hidden_courses {
+            // students only get available courses by default
+            canvas_client.list_courses_in_states(&["available", "completed", "unpublished"], None).await
+        } else if include_concluded {
+            canvas_client.list_courses().await
+        } else {
+            canvas_client.list_courses_in_states(&[], Some("active")).await
+        };
+        let course_list = courses.map_err(|e| match e.downcast_ref::<canvas::CanvasError>() {
+            Some(canvas_error) if canvas_error.is_unauthorized() => e.context("Authentication failed — check your token")
+                .context(ExitStatus::Authentication),
+            _ => e.context("Failed to list courses, check that the canvas url and token are correct"),
+        })?;
+        let mut courses = course_list.courses;
+        if !course_url_ids.is_empty() {
+            courses.retain(|course| course_url_ids.contains(&course.id));
+        }
+        if args.courses_json {
+            courses.retain(|course| course_selected(&args.course, &args.course_id, course) && term_selected(&args.term, &args.year, course));
+            serde_json::to_writer_pretty(std::io::stdout(), &courses)?;
+            status!();
+            return Ok(());
+        }
+        // canvas lists these without the details needed to download them, so say why a course may be missing
+        for (skipped, reason) in [(course_list.access_restricted, "access restricted"), (course_list.inaccessible, "not accessible")] {
+            if skipped > 0 {
+                status!("{} course{} skipped ({})", skipped, if skipped == 1 { "" } else { "s" }, reason);
+            }
+        }
+        if courses.is_empty() {
+            status!("No courses found, nothing to download");
+            return Ok(());
+        }
+        // a prompt would hold up scripts, and courses picked on the command line need no picking
+        if args.interactive && args.course.is_empty() && args.course_id.is_empty() && course_url_ids.is_empty() && std::io::stdout().is_terminal() {
+            args.course_id = pick_courses(&courses)?;
+            if args.course_id.is_empty() {
+                status!("No courses picked, nothing to download");
+                return Ok(());
+            }
+        }
+        courses
+    };
 
-    // do not directly deserialize into canvas::Course objects
-    // there are may be courses that are restricted and not contain the fields needed to deserialise
-    let courses_json = client.get(&courses_link)
-        .bearer_auth(&canvas_token)
-        .send()
-        .await
-        .with_context(|| format!("Something went wrong when reaching {}", &courses_link))?
-        .json::<serde_json::Value>()
-        .await?;
+    let seen_files = match &args.seen_index {
+        Some(path) if path.exists() => load_seen_index(path)?,
+        _ => Vec::new(),
+    };
 
-    let mut courses = vec![];
-    for course_json in courses_json.as_array().unwrap() {
-        if course_json.get("enrollments").is_some() {
-            let course: canvas::Course = serde_json::from_value(course_json.to_owned()).unwrap();
-            courses.push(course);
-        }
-    }
+    let file_states = match &args.state_file {
+        Some(path) if path.exists() => load_state_file(path)?,
+        _ => HashMap::new(),
+    };
 
-    let options = ProcessOptions {
-        canvas_token: canvas_token.clone(),
+    // the events take the place of the progress bars with --output-format json
+    let events = json_output().then(|| Arc::new(emit) as EventHandler);
+
+    let mut options = ProcessOptions {
+        canvas_client: canvas_client.clone(),
         link: String::from(""),
         parent_folder_path: PathBuf::new(),
-        client: client.clone(),
         files_to_download: Arc::new(Mutex::new(Vec::new())),
         download_newer: args.download_newer,
+        max_files: args.max_files,
+        seen_files: Arc::new(seen_files.iter().map(|f| (f.id, f.filepath.clone())).collect()),
+        move_existing: args.move_existing,
+        moved_files: Arc::new(Mutex::new(Vec::new())),
+        file_states: Arc::new(file_states),
+        file_queue: None,
+        folder_filters: Arc::new(args.folder.iter()
+            .map(|folder| folder.split('/')
+                .filter(|name| !name.is_empty())
+                .map(sanitize_filename::sanitize)
+                .collect())
+            .collect()),
+        relative_folder_path: Vec::new(),
+        folder_selected: false,
+        // the tree shows every file on canvas, whether it is downloaded already or not
+        diff: args.diff || args.list,
+        dry_run,
+        canvas_ignore: load_canvas_ignore(&args.destination_folder)?.map(Arc::new),
+        include_globs: Arc::new(include_globs),
+        exclude_globs: Arc::new(exclude_globs),
+        ignore_root: PathBuf::new(),
+        since,
+        skipped_files: args.manifest.as_ref().map(|_| Arc::new(std::sync::Mutex::new(Vec::new()))),
+        traversed: None,
+        destination_template: args.destination_template.clone().map(Arc::new),
+        template_values: Default::default(),
+        destination_folder: payload_folder.clone(),
+        existing_files: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        claimed_paths: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        flatten_folder: None,
+        keep_root_folder: args.keep_root_folder,
+        force: args.force,
+        max_file_size: args.max_file_size,
+        min_file_size: args.min_file_size,
+        size_filtered_files: Default::default(),
+        include_hidden: args.include_hidden,
+        max_path_length: args.max_path_length.map(std::num::NonZeroUsize::get),
+        // which files are found first decides which are kept by --max-files, and which keep their name with --flatten
+        scan_permits: (args.max_files.is_none() && !args.flatten && !args.flatten_all && args.destination_template.is_none())
+            .then(|| Arc::new(tokio::sync::Semaphore::new(traverse::SCAN_CONCURRENCY))),
+        on_event: events.clone(),
+    };
+
+    // nicknames are only used for naming, so failing to fetch them should not stop the download
+    let nicknames = if args.use_nicknames {
+        fetch_course_nicknames(&canvas_client)
+            .await
+            .unwrap_or_else(|e| {
+                warning!("Failed to fetch course nicknames, falling back to course codes\n{:?}", e);
+                HashMap::new()
+            })
+    } else {
+        HashMap::new()
+    };
+
+    let queue_file = match (&args.queue_file, resumed_queue) {
+        (Some(path), Some(entries)) => Some(canvas::QueueFile::new(path.clone(), entries)),
+        // a new run starts a new queue
+        (Some(path), None) => {
+            let queue_file = canvas::QueueFile::new(path.clone(), Vec::new());
+            queue_file.save()?;
+            Some(queue_file)
+        },
+        (None, _) => None,
     };
 
-    println!("Courses found:");
+    // Tokio uses the number of cpus as num of work threads in the default runtime
+    let num_workers = args.max_concurrent_downloads.map_or_else(num_cpus::get, |n| n.get());
+    let download_options = DownloadOptions {
+        canvas_token: canvas_token.clone(),
+        token_urls: [&canvas_url, &api_url].into_iter().filter_map(|url| reqwest::Url::parse(url).ok()).collect(),
+        client: client.clone(),
+        progress_bars: progress_bars.clone(),
+        downloaded_file_ids: Arc::new(Mutex::new(HashSet::new())),
+        failed_downloads: Arc::new(Mutex::new(Vec::new())),
+        empty_downloads: Arc::new(AtomicUsize::new(0)),
+        timings: args.timings.as_ref().map(|_| Arc::new(Mutex::new(Vec::new()))),
+        download_records: args.manifest.as_ref().map(|_| Arc::new(Mutex::new(Vec::new()))),
+        rate_limiter: canvas_client.rate_limiter().clone(),
+        timeout,
+        renamed_files: Arc::new(Mutex::new(HashMap::new())),
+        verify: args.verify,
+        downloaded_bytes: Arc::new(AtomicU64::new(0)),
+        etags: Arc::new(std::sync::Mutex::new(if dry_run { HashMap::new() } else { load_etags(&args.destination_folder)? })),
+        not_modified_file_ids: Arc::new(Mutex::new(HashSet::new())),
+        send_etags: !args.force,
+        checksums: args.bagit.then(|| Arc::new(Mutex::new(HashMap::new()))),
+        host_limiter: HostLimiter::new(args.concurrency_per_host.get()),
+        speed_limiter: SpeedLimiter::new(args.max_speed),
+        circuit_breaker: CircuitBreaker::new(args.circuit_breaker_failures.get(), std::time::Duration::from_secs(args.circuit_breaker_cooldown)),
+        queue_file: queue_file.clone(),
+        segments: args.segments.get(),
+        retries: args.retries,
+        // starting clean also means not picking up where a partial download left off
+        resume: !args.no_resume && !args.force,
+        on_event: events,
+    };
+    let mut join_handles = Vec::new();
+
+    // files start downloading as soon as they are found, instead of after every course has been scanned
+    // with --parallel-scan-and-download the downloads start straight away, otherwise once scanning is done
+    let mut downloads_started_at = std::time::Instant::now();
+    let overall_progress = if args.parallel_scan_and_download && !args.resume {
+        let overall_progress = progress_bars.add(ProgressBar::new(0));
+        overall_progress.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner} {pos}/{len} files - still looking for files").unwrap()
+        );
+        overall_progress.enable_steady_tick(std::time::Duration::from_millis(100));
+
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        options.file_queue = Some(FileQueue::new(sender, overall_progress.clone(), queue_file.clone()));
+        join_handles = download::spawn_download_workers(&download_options, receiver, num_workers, &overall_progress);
+        Some(overall_progress)
+    } else {
+        None
+    };
+
+    let mut providers: Vec<Box<dyn ContentProvider>> = vec![Box::new(FilesProvider)];
+    if args.front_page {
+        providers.push(Box::new(FrontPageProvider { markdown: args.html_to_markdown }));
+    }
+    if args.media {
+        providers.push(Box::new(MediaProvider { quality: args.media_quality }));
+    }
+    if args.modules {
+        providers.push(Box::new(ModulesProvider));
+    }
+    if args.pages {
+        providers.push(Box::new(PagesProvider));
+    }
+    if args.syllabus {
+        providers.push(Box::new(SyllabusProvider));
+    }
+    if args.discussions {
+        providers.push(Box::new(DiscussionsProvider));
+    }
+    if args.announcements {
+        providers.push(Box::new(AnnouncementsProvider));
+    }
+
+    if !courses.is_empty() {
+        status!("Courses found:");
+    }
+    let mut course_folder_paths = Vec::new();
+    let mut document_sizes = BTreeMap::new();
+    let mut metadata_dump = canvas::MetadataDump::default();
     for course in courses {
-        println!("  * {} - {}", course.course_code, course.name);
+        let selected = course_selected(&args.course, &args.course_id, &course) && term_selected(&args.term, &args.year, &course);
+        let notes = [
+            course.workflow_state.as_deref().filter(|state| *state != "available"),
+            (!selected).then_some("skipped"),
+        ].into_iter().flatten().collect::<Vec<_>>();
+        if notes.is_empty() {
+            status!("  * {} - {}", course.course_code, course.name);
+        } else {
+            status!("  * {} - {} ({})", course.course_code, course.name, notes.join(", "));
+        }
+        if !selected {
+            continue;
+        }
 
-        let course_folder_path = args
-            .destination_folder
-            .join(course.course_code.replace("/", "_"));
-        if !course_folder_path.exists() {
-            std::fs::create_dir(&course_folder_path).with_context(|| {
+        let course_folder_name = truncate_name(course_folder_name(&course, nicknames.get(&course.id), args.folder_naming), args.max_path_length.map(std::num::NonZeroUsize::get));
+        let course_folder_path = match course_map.get(&course.id) {
+            Some(course_folder_path) => course_folder_path.clone(),
+            None => payload_folder.join(course_folder_name),
+        };
+        course_folder_paths.push(course_folder_path.clone());
+        // the template decides the folders the files go into, which are created as the files are found
+        if !dry_run && args.destination_template.is_none() && !course_folder_path.exists() {
+            std::fs::create_dir_all(&course_folder_path).with_context(|| {
                 format!(
                     "Failed to create directory: {}",
                     course_folder_path.to_string_lossy()
                 )
-            })?;
+            }).context(ExitStatus::Destination)?;
         }
 
-        // this api gives us the root folder
-        let course_folders_link = format!("{}/{}/folders/by_path/", &courses_link, course.id);
+        let mut ctx = CourseContext {
+            options: traversal_options(&options, &payload_folder, &course_folder_path, args.flatten, args.flatten_all),
+            course,
+            course_folder_path,
+            courses_link: courses_link.clone(),
+        };
+        ctx.options.template_values = Arc::new(canvas::TemplateValues {
+            course_code: ctx.course.course_code.clone(),
+            course_name: ctx.course.name.clone(),
+            term: ctx.course.term.as_ref().map_or_else(|| String::from("No term"), |term| term.name.clone()),
+            course_folder_path: ctx.course_folder_path.clone(),
+        });
+        // each course gets a tree of its own
+        ctx.options.traversed = args.dump_metadata.as_ref().map(|_| Default::default());
+        // the cap on the number of files covers every course
+        document_sizes.extend(collect_course(&mut ctx, &providers, args.max_files).await);
+        if let Some(traversed) = ctx.options.traversed.take() {
+            let traversed = std::mem::take(&mut *traversed.lock().unwrap());
+            metadata_dump.courses.push(canvas::CourseTree { course: ctx.course, folders: traversed.into_folder_trees() });
+        }
+    }
 
-        let mut new_options = options.clone();
-        new_options.link = course_folders_link;
-        new_options.parent_folder_path = course_folder_path;
+    if let Some(folder_id) = args.folder_id.filter(|_| !args.resume) {
+        let folder = canvas_client.folder(folder_id).await.map_err(|e| match e.downcast_ref::<canvas::CanvasError>() {
+            Some(canvas_error) if canvas_error.is_unauthorized() => e.context(format!("No access to the folder with id {}", folder_id))
+                .context(ExitStatus::Authentication),
+            _ => e.context(format!("Failed to get the folder with id {}", folder_id)),
+        })?;
+        // the folder gets a folder of its own in the destination, like a course does
+        let folder_path = payload_folder.join(truncate_name(sanitize_filename::sanitize(&folder.name), args.max_path_length.map(std::num::NonZeroUsize::get)));
+        status!("Folder found:");
+        status!("  * {} - {}", folder.id, folder.name);
+        if !dry_run && !folder_path.exists() {
+            std::fs::create_dir_all(&folder_path)
+                .with_context(|| format!("Failed to create directory: {}", folder_path.to_string_lossy()))
+                .context(ExitStatus::Destination)?;
+        }
+        course_folder_paths.push(folder_path.clone());
+        // the folder is walked like the root folder of a course, with its contents straight in its own folder
+        let mut folder_options = traversal_options(&options, &payload_folder, &folder_path, args.flatten, args.flatten_all);
+        folder_options.parent_folder_path = folder_path;
+        folder_options.keep_root_folder = false;
+        folder_options.traversed = args.dump_metadata.as_ref().map(|_| Default::default());
+        walk_folders(&folder_options, process_folder(&folder_options, folder, true)).await;
+        if let Some(traversed) = folder_options.traversed.take() {
+            let traversed = std::mem::take(&mut *traversed.lock().unwrap());
+            metadata_dump.folders.extend(traversed.into_folder_trees());
+        }
+    }
 
-        process_folders(new_options).await;
+    if let Some(path) = &args.dump_metadata {
+        save_metadata(path, &metadata_dump)?;
     }
 
-    println!("");
+    // closes the queue so the workers stop once it is empty
+    options.file_queue = None;
 
-    // Tokio uses the number of cpus as num of work threads in the default runtime
-    let num_worker_threads = num_cpus::get();
-    let files_to_download = Arc::new(Arc::try_unwrap(options.files_to_download).unwrap().into_inner());
-    let num_worker_extra_work = files_to_download.len() % num_worker_threads;
-    let min_work = files_to_download.len() / num_worker_threads;
-    let progress_bars = Arc::new(MultiProgress::new());
+    status!();
 
-    println!("Downloading {} file{}", files_to_download.len(), if files_to_download.len() == 1 { "" } else { "s" } );
+    let mut files_to_download = std::mem::take(&mut *options.files_to_download.lock().await);
+    if let Some(queue_file) = queue_file.as_ref().filter(|_| args.resume) {
+        files_to_download = queue_file.pending();
+    }
+    if args.list {
+        print_tree(&args.destination_folder, &files_to_download, &course_folder_paths, &document_sizes);
+        return Ok(());
+    }
 
-    let mut join_handles = Vec::new();
-    let atomic_file_index = Arc::new(AtomicUsize::new(0));
-
-    // We manually limit each worker thread to only deal with 1 file at all time to avoid
-    // spamming http requests
-    for i in 0..num_worker_threads {
-        let mut work = min_work;
-        if i < num_worker_extra_work {
-            work += 1;
-        }
-        let canvas_token = canvas_token.clone();
-        let client = client.clone();
-        let files_to_download = files_to_download.clone();
-        let progress_bars = progress_bars.clone();
-        let atomic_file_index = atomic_file_index.clone();
-        let handle = tokio::spawn(async move {
-            for _ in 0..work {
-                let file_index = atomic_file_index.fetch_add(1, Ordering::Relaxed);
-                let canvas_file = files_to_download.get(file_index).unwrap();
-
-                // We need to determine the file size before we download, so we can create a ProgressBar
-                // A Header request for the CONTENT_LENGTH header gets us the file size
-                let download_size = {
-                    let resp = client.head(&canvas_file.url).send().await.unwrap();
-                    if resp.status().is_success() {
-                        resp.headers() // Gives us the HeaderMap
-                            .get(header::CONTENT_LENGTH) // Gives us an Option containing the HeaderValue
-                            .and_then(|ct_len| ct_len.to_str().ok()) // Unwraps the Option as &str
-                            .and_then(|ct_len| ct_len.parse().ok()) // Parses the Option as u64
-                            .unwrap_or(0) // Fallback to 0
-                    } else {
-                        // We return an Error if something goes wrong here
-                        println!("Failed to download {}", canvas_file.display_name);
-                        continue
-                    }
-                };
-
-                let progress_bar = progress_bars.add(ProgressBar::new(download_size));
-
-                let mut style_template = "[{bar:20.cyan/blue}] {bytes}/{total_bytes} - {bytes_per_sec} - {msg}";
-                termsize::get().map(|size| {
-                    // arbitrary 100
-                    if size.cols < 100 {
-                        style_template = "[{wide_bar:.cyan/blue}] {total_bytes} - {msg}";
-                    }
-                });
-                progress_bar.set_style(
-                    ProgressStyle::default_bar()
-                        .template(style_template).unwrap()
-                        .progress_chars("=>-")
-                );
-
-                let message = format!("{}", canvas_file.display_name);
-
-                progress_bar.set_message(message);
-
-                let mut file = std::fs::File::create(&canvas_file.filepath).unwrap();
-                // canvas also provides a modified_time of the file but updated_at should be more proper
-                // as it probably represents the upload date of the file which is more apt for determining
-                // if the file was changed since downloading it
-                match DateTime::parse_from_rfc3339(&canvas_file.updated_at) {
-                    Ok(updated_at) => {
-                        match filetime::set_file_mtime(
-                            &canvas_file.filepath,
-                            filetime::FileTime::from_unix_time(
-                                updated_at.timestamp(),
-                                updated_at.timestamp_subsec_nanos())) {
-                            Err(_) => {
-                                println!("Failed to set modified time of {} with updated_at of {}", canvas_file.display_name, canvas_file.updated_at);
-                            },
-                            _ => {}
-                        };
-                    },
-                    Err(_) => {
-                        println!("Failed to parse updated_at time for {}, {}", canvas_file.display_name, canvas_file.updated_at);
-                        continue;
-                    }
-                };
-
-                let mut file_response = client.get(&canvas_file.url)
-                    .bearer_auth(&canvas_token)
-                    .send()
-                    .await
-                    .with_context(|| format!("Something went wrong when reaching {}", &canvas_file.url)).unwrap();
-
-                while let Some(chunk) = file_response.chunk().await.unwrap() {
-                    progress_bar.inc(chunk.len() as u64);
-                    let mut cursor = std::io::Cursor::new(chunk);
-                    std::io::copy(&mut cursor, &mut file).unwrap();
-                }
-                progress_bar.finish();
-            }
-        });
-        
-        join_handles.push(handle);
+    let existing_files = std::mem::take(&mut *options.existing_files.lock().unwrap());
+    let duplicate_files = dedupe_files(&mut files_to_download, &existing_files);
+    let size_filtered_files = options.size_filtered_files.lock().unwrap().clone();
+    if size_filtered_files.count > 0 {
+        status!("Skipped {} file{} ({}) outside of the size limits", size_filtered_files.count, if size_filtered_files.count == 1 { "" } else { "s" }, HumanBytes(size_filtered_files.bytes));
+    }
+
+    if args.diff {
+        let document_paths = document_sizes.into_keys().collect();
+        print_diff(&args.destination_folder, &files_to_download, &course_folder_paths, &document_paths, &options)?;
+        return Ok(());
+    }
+
+    if args.dry_run {
+        sort_files(&mut files_to_download, args.sort);
+        print_dry_run(&args.destination_folder, &files_to_download, &document_sizes);
+        return Ok(());
+    }
+
+    // with --parallel-scan-and-download the files were queued as they were found
+    if overall_progress.is_none() {
+        sort_files(&mut files_to_download, args.sort);
+        if let Some(queue_file) = queue_file.as_ref().filter(|_| !args.resume) {
+            queue_file.add(&files_to_download)?;
+        }
+        status!("Downloading {} file{}", files_to_download.len(), if files_to_download.len() == 1 { "" } else { "s" } );
+        for file in &files_to_download {
+            emit(&Event::Discovered { file, path: &file.filepath });
+        }
+    }
+
+    let scanned_in_parallel = overall_progress.is_some();
+    // sits above the bars of the files being downloaded
+    let overall_progress = overall_progress.unwrap_or_else(|| progress_bars.add(ProgressBar::new(files_to_download.len() as u64)));
+    overall_progress.set_style(
+        ProgressStyle::default_bar()
+            .template("[{bar:20.green/white}] {pos}/{len} files, {msg} - {elapsed_precise}").unwrap()
+            .progress_chars("=>-")
+    );
+    overall_progress.set_message(HumanBytes(download_options.downloaded_bytes.load(Ordering::Relaxed)).to_string());
+
+    if !scanned_in_parallel {
+        downloads_started_at = std::time::Instant::now();
+        download::download_files(&download_options, &files_to_download, num_workers, &overall_progress).await;
     }
 
     for handle in join_handles {
         handle.await?;
     }
 
-    for canvas_file in Arc::try_unwrap(files_to_download).unwrap() {
-        println!("Downloaded {} to {}", canvas_file.display_name, canvas_file.filepath.to_string_lossy());
+    // nothing to download leaves no bar behind
+    if overall_progress.length() == Some(0) {
+        overall_progress.finish_and_clear();
+    } else {
+        overall_progress.finish();
+    }
+
+    if let Some(queue_file) = &queue_file {
+        queue_file.save()?;
+    }
+
+    for (id, filepath) in std::mem::take(&mut *download_options.renamed_files.lock().await) {
+        for file in files_to_download.iter_mut().filter(|f| f.id == id) {
+            file.filepath = filepath.clone();
+        }
+    }
+
+    let downloaded_file_ids = download_options.downloaded_file_ids.lock().await;
+    let not_modified_file_ids = download_options.not_modified_file_ids.lock().await;
+
+    for canvas_file in files_to_download.iter().filter(|f| downloaded_file_ids.contains(&f.id)) {
+        status!("Downloaded {} to {}", canvas_file.display_name, canvas_file.filepath.to_string_lossy());
+    }
+    if args.link_duplicates {
+        link_duplicate_files(&files_to_download, &existing_files, &duplicate_files);
+    }
+    let empty_downloads = download_options.empty_downloads.load(Ordering::Relaxed);
+    if empty_downloads > 0 {
+        status!("{} of the files {} empty on canvas", empty_downloads, if empty_downloads == 1 { "was" } else { "were" });
+    }
+    let not_modified_files = files_to_download.iter().filter(|f| not_modified_file_ids.contains(&f.id)).count();
+    if not_modified_files > 0 {
+        status!("{} of the files {} not modified since the last download", not_modified_files, if not_modified_files == 1 { "was" } else { "were" });
+    }
+
+    let downloaded_files = files_to_download.iter().filter(|f| downloaded_file_ids.contains(&f.id)).count();
+    status!();
+    status!(
+        "Downloaded {} file{} ({}) in {}, skipped {} already downloaded, {} failed",
+        downloaded_files,
+        if downloaded_files == 1 { "" } else { "s" },
+        HumanBytes(download_options.downloaded_bytes.load(Ordering::Relaxed)),
+        HumanDuration(downloads_started_at.elapsed()),
+        existing_files.len() + not_modified_files,
+        files_to_download.len() - downloaded_files - not_modified_files,
+    );
+    emit(&Event::Summary {
+        downloaded: downloaded_files,
+        skipped: existing_files.len() + not_modified_files,
+        failed: files_to_download.len() - downloaded_files - not_modified_files,
+        bytes: download_options.downloaded_bytes.load(Ordering::Relaxed),
+        seconds: downloads_started_at.elapsed().as_secs_f64(),
+    });
+
+    if let Some(path) = &args.state_file {
+        let mut file_states = (*options.file_states).clone();
+        for file in files_to_download.iter().filter(|f| downloaded_file_ids.contains(&f.id) || not_modified_file_ids.contains(&f.id)) {
+            file_states.insert(file.id, canvas::FileState {
+                id: file.id,
+                size: file.size,
+                updated_at: file.updated_at,
+                filepath: file.filepath.clone(),
+            });
+        }
+        save_state_file(path, file_states)?;
+    }
+
+    if let Some(path) = &args.seen_index {
+        let moved_files = std::mem::take(&mut *options.moved_files.lock().await);
+        let downloaded_files = files_to_download.into_iter()
+            .filter(|f| downloaded_file_ids.contains(&f.id))
+            .map(|f| canvas::SeenFile { id: f.id, filepath: Some(f.filepath) });
+        // later entries take precedence, so moved and downloaded files record their new path
+        save_seen_index(path, seen_files.into_iter().chain(moved_files).chain(downloaded_files))?;
+    }
+
+    if let (Some(path), Some(timings)) = (&args.timings, &download_options.timings) {
+        save_timings(path, &timings.lock().await)?;
+    }
+
+    if let (Some(path), Some(download_records)) = (&args.manifest, &download_options.download_records) {
+        let mut download_records = std::mem::take(&mut *download_records.lock().await);
+        if let Some(skipped_files) = &options.skipped_files {
+            download_records.extend(skipped_files.lock().unwrap().iter()
+                .map(|file| canvas::DownloadRecord::new(file, canvas::DownloadStatus::SkippedExisting)));
+        }
+        download_records.sort_by(|a, b| a.filepath.cmp(&b.filepath));
+        save_manifest(path, &download_records)?;
+    }
+
+    save_etags(&args.destination_folder, &download_options.etags.lock().unwrap())?;
+
+    if let Some(checksums) = &download_options.checksums {
+        write_bag(&args.destination_folder, &*checksums.lock().await)?;
+    }
+
+    let mut failed_downloads = std::mem::take(&mut *download_options.failed_downloads.lock().await);
+    if !failed_downloads.is_empty() {
+        failed_downloads.sort_by(|a, b| a.file.filepath.cmp(&b.file.filepath));
+        warning!();
+        warning!("Failed to download {} file{}:", failed_downloads.len(), if failed_downloads.len() == 1 { "" } else { "s" });
+        for failed_download in &failed_downloads {
+            warning!("  * {}: {:#}", failed_download.file.filepath.to_string_lossy(), failed_download.error);
+        }
+        return Err(anyhow::anyhow!("{} file{} failed to download", failed_downloads.len(), if failed_downloads.len() == 1 { "" } else { "s" })
+            .context(ExitStatus::PartialDownload));
+    }
+
+    // only kept for runs that use --since, so other runs leave nothing behind in the destination
+    if record_last_sync {
+        save_last_sync(&args.destination_folder, run_started_at)?;
     }
 
     Ok(())
 }
 
-// async recursion needs boxing
-fn process_folders(options: ProcessOptions) -> BoxFuture<'static, ()> {
-    async move {
-        let canvas_token = &options.canvas_token;
-        let folders_result = options.client.get(&options.link)
-            .bearer_auth(&canvas_token)
-            .send()
-            .await
-            .with_context(|| format!("Something went wrong when reaching {}", &options.link)).unwrap()
-            .json::<canvas::FolderResult>()
-            .await;
-        
-        match folders_result {
-            Ok(canvas::FolderResult::Ok(folders)) => {
-                for folder in folders {
-                    // println!("  * {} - {}", folder.id, folder.name);
-                    let sanitized_folder_name = sanitize_filename::sanitize(folder.name);
-                    // if the folder has no parent, it is the root folder of a course
-                    // so we avoid the extra directory nesting by not appending the root folder name
-                    let folder_path = if folder.parent_folder_id.is_some() {
-                        options.parent_folder_path.clone().join(sanitized_folder_name)
-                    } else {
-                        options.parent_folder_path.clone()
-                    };
-                    if !folder_path.exists() {
-                        std::fs::create_dir(&folder_path)
-                            .with_context(|| format!("Failed to create directory: {}", folder_path.to_string_lossy())).unwrap();
-                    }
-
-                    let mut new_options = options.clone();
-                    new_options.link = folder.files_url.clone();
-                    new_options.parent_folder_path = folder_path.clone();
-                    process_files(new_options).await;
-        
-                    let mut new_options = options.clone();
-                    new_options.link = folder.folders_url.clone();
-                    new_options.parent_folder_path = folder_path.clone();
-                    process_folders(new_options).await;
-                }
-            },
-            Ok(canvas::FolderResult::Err{status}) => {
-                let course_has_no_folders = status == "unauthorized";
-                if !course_has_no_folders {
-                    println!("Failed to access folders at link:{}, path:{}, status:{}", options.link, options.parent_folder_path.to_string_lossy(), status);
-                }
-            },
-            Err(e) => {
-                println!("Failed to deserialize folders at link:{}, path:{}\n{:?}", &options.link, &options.parent_folder_path.to_string_lossy(), e);
+// the seen index is either a json array of file ids / objects with an "id" field (such as a previous
+// seen index), or a csv whose first column is the file id
+fn load_seen_index(path: &PathBuf) -> Result<Vec<canvas::SeenFile>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read seen index: {}", path.to_string_lossy()))?;
+
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) {
+        // skip anything that is not an id, such as a header row
+        let seen_files = contents.lines()
+            .filter_map(|line| line.split(',').next()?.trim().trim_matches('"').parse().ok())
+            .map(|id| canvas::SeenFile { id, filepath: None })
+            .collect();
+        return Ok(seen_files);
+    }
+
+    let entries: Vec<canvas::SeenIndexEntry> = serde_json::from_str(&contents)
+        .with_context(|| format!("Seen index is not valid json: {}", path.to_string_lossy()))?;
+    Ok(entries.into_iter()
+        .map(|entry| match entry {
+            canvas::SeenIndexEntry::Id(id) => canvas::SeenFile { id, filepath: None },
+            canvas::SeenIndexEntry::File(file) => file,
+        })
+        .collect())
+}
+
+fn save_seen_index(path: &PathBuf, seen_files: impl Iterator<Item = canvas::SeenFile>) -> Result<()> {
+    let mut index = BTreeMap::new();
+    for seen_file in seen_files {
+        index.insert(seen_file.id, seen_file.filepath);
+    }
+
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to write seen index: {}", path.to_string_lossy()))?;
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) {
+        let mut writer = std::io::BufWriter::new(file);
+        writeln!(writer, "id")?;
+        for id in index.keys() {
+            writeln!(writer, "{}", id)?;
+        }
+    } else {
+        let seen_files = index.into_iter()
+            .map(|(id, filepath)| canvas::SeenFile { id, filepath })
+            .collect::<Vec<_>>();
+        serde_json::to_writer_pretty(file, &seen_files)?;
+    }
+    Ok(())
+}
+
+const LAST_SYNC_FILE: &str = ".last-sync";
+
+const SEEN_INDEX_FILE: &str = ".canvas-seen.json";
+
+fn load_last_sync(destination_folder: &std::path::Path) -> Result<Option<DateTime<Utc>>> {
+    let path = destination_folder.join(LAST_SYNC_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read last sync: {}", path.to_string_lossy()))?;
+    let last_sync = DateTime::parse_from_rfc3339(contents.trim())
+        .with_context(|| format!("Last sync is not a valid timestamp: {}", path.to_string_lossy()))?;
+    Ok(Some(last_sync.with_timezone(&Utc)))
+}
+
+fn save_last_sync(destination_folder: &std::path::Path, last_sync: DateTime<Utc>) -> Result<()> {
+    let path = destination_folder.join(LAST_SYNC_FILE);
+    std::fs::write(&path, format!("{}\n", last_sync.to_rfc3339()))
+        .with_context(|| format!("Failed to write last sync: {}", path.to_string_lossy()))
+}
+
+const ETAGS_FILE: &str = ".canvas-etags.json";
+
+fn load_etags(destination_folder: &std::path::Path) -> Result<HashMap<u32, String>> {
+    let path = destination_folder.join(ETAGS_FILE);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let file = std::fs::File::open(&path)
+        .with_context(|| format!("Failed to read etags: {}", path.to_string_lossy()))?;
+    serde_json::from_reader(file)
+        .with_context(|| format!("Etags are not valid json: {}", path.to_string_lossy()))
+}
+
+fn save_etags(destination_folder: &std::path::Path, etags: &HashMap<u32, String>) -> Result<()> {
+    let path = destination_folder.join(ETAGS_FILE);
+    if etags.is_empty() && !path.exists() {
+        return Ok(());
+    }
+    // sorted by id, so the file does not change from run to run when the etags do not
+    let etags: BTreeMap<_, _> = etags.iter().collect();
+    let file = std::fs::File::create(&path)
+        .with_context(|| format!("Failed to write etags: {}", path.to_string_lossy()))?;
+    serde_json::to_writer_pretty(file, &etags)?;
+    Ok(())
+}
+
+// the date is taken to be in the local timezone, as that is the day the user has in mind
+fn start_of_day(date: NaiveDate) -> Result<DateTime<Utc>> {
+    let start = date.and_hms_opt(0, 0, 0).unwrap_or_default();
+    Local.from_local_datetime(&start)
+        .earliest()
+        .map(|start| start.with_timezone(&Utc))
+        .with_context(|| format!("{} has no midnight in the local timezone", date))
+}
+
+fn load_state_file(path: &PathBuf) -> Result<HashMap<u32, canvas::FileState>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to read state file: {}", path.to_string_lossy()))?;
+    let file_states: Vec<canvas::FileState> = serde_json::from_reader(file)
+        .with_context(|| format!("State file is not valid json: {}", path.to_string_lossy()))?;
+    Ok(file_states.into_iter().map(|state| (state.id, state)).collect())
+}
+
+fn save_state_file(path: &PathBuf, file_states: HashMap<u32, canvas::FileState>) -> Result<()> {
+    let mut file_states = file_states.into_values().collect::<Vec<_>>();
+    file_states.sort_by_key(|state| state.id);
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to write state file: {}", path.to_string_lossy()))?;
+    serde_json::to_writer_pretty(file, &file_states)?;
+    Ok(())
+}
+
+fn load_queue_file(path: &PathBuf) -> Result<Vec<canvas::QueueEntry>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to read queue file: {}", path.to_string_lossy()))?;
+    let mut entries = serde_json::from_reader(file)
+        .with_context(|| format!("Queue file is not valid json: {}", path.to_string_lossy()))?;
+    canvas::QueueFile::replay_log(path, &mut entries)
+        .with_context(|| format!("Failed to read the log of queue file: {}", path.to_string_lossy()))?;
+    Ok(entries)
+}
+
+// quote every field so commas in paths do not break the csv
+fn csv_field(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
+
+fn save_timings(path: &PathBuf, timings: &[canvas::FileTiming]) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to write timings: {}", path.to_string_lossy()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    writeln!(writer, "id,filepath,host,bytes,started_at,finished_at,seconds,mb_per_sec")?;
+    for timing in timings {
+        let seconds = timing.duration.as_secs_f64();
+        let mb_per_sec = if seconds > 0.0 { timing.bytes as f64 / 1_000_000.0 / seconds } else { 0.0 };
+        writeln!(writer, "{},{},{},{},{},{},{:.3},{:.3}",
+            timing.id,
+            csv_field(&timing.filepath.to_string_lossy()),
+            csv_field(&timing.host),
+            timing.bytes,
+            timing.started_at.to_rfc3339(),
+            timing.finished_at.to_rfc3339(),
+            seconds,
+            mb_per_sec)?;
+    }
+    Ok(())
+}
+
+// written as csv when the path ends in .csv, and as json otherwise
+fn save_manifest(path: &PathBuf, download_records: &[canvas::DownloadRecord]) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to write manifest: {}", path.to_string_lossy()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) {
+        serde_json::to_writer_pretty(&mut writer, download_records)?;
+        writeln!(writer)?;
+        return Ok(());
+    }
+
+    writeln!(writer, "id,display_name,filepath,size,url,status")?;
+    for record in download_records {
+        writeln!(writer, "{},{},{},{},{},{}",
+            record.id,
+            csv_field(&record.display_name),
+            csv_field(&record.filepath.to_string_lossy()),
+            record.size,
+            csv_field(&record.url),
+            record.status.as_str())?;
+    }
+    Ok(())
+}
+
+fn save_metadata(path: &PathBuf, metadata_dump: &canvas::MetadataDump) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to write metadata: {}", path.to_string_lossy()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, metadata_dump)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+// writes the metadata files that make the destination a BagIt bag (RFC 8493)
+// files that were not downloaded in this run keep their checksum from the previous manifest, or are hashed from disk
+fn write_bag(bag_folder: &std::path::Path, checksums: &HashMap<PathBuf, String>) -> Result<()> {
+    let manifest_path = bag_folder.join("manifest-sha256.txt");
+    let mut previous_checksums = HashMap::new();
+    if let Ok(manifest) = std::fs::read_to_string(&manifest_path) {
+        for line in manifest.lines() {
+            if let Some((checksum, path)) = line.split_once(char::is_whitespace) {
+                previous_checksums.insert(path.trim().to_string(), checksum.to_string());
             }
         }
-    }.boxed()
-}
-
-async fn process_files(options: ProcessOptions) {
-    let files_result = options.client.get(&options.link)
-        .bearer_auth(&options.canvas_token)
-        .send()
-        .await
-        .with_context(|| format!("Something went wrong when reaching {}", &options.link)).unwrap()
-        .json::<canvas::FileResult>()
-        .await;
-    
-    fn updated(filepath: &PathBuf, new_modified: &str) -> bool {
-        (|| -> Result<bool> {
-            let old_modified = std::fs::metadata(filepath)?.modified()?;
-            let new_modified = std::time::SystemTime::from(DateTime::parse_from_rfc3339(new_modified)?);
-            let updated = old_modified < new_modified;
-            if updated {
-                println!("Found update for {filepath:?}. Use -n to download updated files.");
+    }
+
+    let mut payload_files = Vec::new();
+    collect_files(&bag_folder.join("data"), &mut payload_files)?;
+    payload_files.sort();
+
+    let mut manifest = String::new();
+    let mut payload_bytes = 0;
+    for path in &payload_files {
+        payload_bytes += std::fs::metadata(path)?.len();
+        let relative_path = path.strip_prefix(bag_folder)?
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let checksum = match checksums.get(path).or_else(|| previous_checksums.get(&relative_path)) {
+            Some(checksum) => checksum.clone(),
+            None => {
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+                format!("{:x}", hasher.finalize())
             }
-            Ok(updated)
-        })().unwrap_or(false)
-    }
-    
-    match files_result {
-        Ok(canvas::FileResult::Ok(mut files)) => {
-            for file in &mut files {
-                let sanitized_filename = sanitize_filename::sanitize(&file.display_name);
-                file.filepath = options.parent_folder_path.join(sanitized_filename);
+        };
+        manifest.push_str(&format!("{}  {}\n", checksum, relative_path));
+    }
+
+    std::fs::write(bag_folder.join("bagit.txt"), "BagIt-Version: 1.0\nTag-File-Character-Encoding: UTF-8\n")?;
+    std::fs::write(&manifest_path, manifest)?;
+    std::fs::write(bag_folder.join("bag-info.txt"), format!(
+        "Bag-Software-Agent: canvas-downloader {}\nBagging-Date: {}\nPayload-Oxum: {}.{}\n",
+        env!("CARGO_PKG_VERSION"),
+        chrono::Local::now().format("%Y-%m-%d"),
+        payload_bytes,
+        payload_files.len()))?;
+    Ok(())
+}
+
+fn check_writable(folder: &std::path::Path) -> Result<()> {
+    let probe_path = folder.join(".canvas-downloader-write-check");
+    std::fs::write(&probe_path, b"")
+        .with_context(|| format!("Cannot write to the destination folder: {}", folder.to_string_lossy()))?;
+    std::fs::remove_file(&probe_path)
+        .with_context(|| format!("Failed to remove {}", probe_path.to_string_lossy()))?;
+    Ok(())
+}
+
+fn collect_files(folder: &std::path::Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.extension().is_none_or(|extension| extension != PARTIAL_EXTENSION) {
+            // downloads that did not finish are not files of the course yet
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// written to stdout as a line of json with --output-format json
+fn emit(event: &Event) {
+    if !json_output() {
+        return;
+    }
+    match serde_json::to_string(event) {
+        Ok(line) => println!("{}", redact(&line)),
+        Err(e) => eprintln!("Failed to write the event as json\n{:?}", e),
+    }
+}
+
+// log lines go to stderr, with the progress bars cleared while they are written so the two do not garble each other
+#[derive(Clone)]
+struct ProgressBarWriter(Arc<MultiProgress>);
+
+impl Write for ProgressBarWriter {
+    // each log line comes in one write, so a token cannot be split across two
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let line = redact(&String::from_utf8_lossy(buf));
+        self.0.suspend(|| std::io::stderr().write_all(line.as_bytes()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for ProgressBarWriter {
+    type Writer = ProgressBarWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+fn init_logging(verbose: u8, progress_bars: Arc<MultiProgress>) {
+    let level = match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        _ => LevelFilter::DEBUG,
+    };
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer()
+            .with_writer(ProgressBarWriter(progress_bars))
+            .with_target(false)
+            .with_ansi(std::io::stderr().is_terminal())
+            .without_time())
+        // only our own logs, reqwest and friends are not of interest here
+        .with(Targets::new().with_target("canvas_downloader", level))
+        .init();
+}
+
+// without a proxy given, reqwest picks one up from HTTP_PROXY, HTTPS_PROXY or ALL_PROXY
+fn build_client(timeout: std::time::Duration, proxy: Option<&reqwest::Proxy>) -> reqwest::ClientBuilder {
+    let builder = reqwest::Client::builder()
+        .user_agent(concat!("canvas-downloader/", env!("CARGO_PKG_VERSION")))
+        .connect_timeout(timeout)
+        .tcp_keepalive(std::time::Duration::from_secs(60));
+    match proxy {
+        Some(proxy) => builder.proxy(proxy.clone()),
+        None => builder,
+    }
+}
+
+fn keyring_entry(canvas_url: &str) -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new("canvas-downloader", canvas_url.trim_end_matches('/'))
+}
+
+fn print_dry_run(destination_folder: &std::path::Path, files: &[canvas::File], document_sizes: &BTreeMap<PathBuf, u64>) {
+    let display_path = |path: &std::path::Path| path.strip_prefix(destination_folder).unwrap_or(path).to_string_lossy().into_owned();
+
+    let mut total_size = 0;
+    for file in files {
+        println!("{} ({})", display_path(&file.filepath), HumanBytes(file.size));
+        total_size += file.size;
+    }
+    for (filepath, size) in document_sizes {
+        println!("{} ({})", display_path(filepath), HumanBytes(*size));
+        total_size += size;
+    }
+
+    let count = files.len() + document_sizes.len();
+    println!("\n{} file{} would be downloaded, {} in total", count, if count == 1 { "" } else { "s" }, HumanBytes(total_size));
+}
+
+#[derive(Default)]
+struct TreeNode {
+    // folders have no size
+    size: Option<u64>,
+    children: BTreeMap<String, TreeNode>,
+}
+
+fn print_tree(destination_folder: &std::path::Path, files: &[canvas::File], course_folder_paths: &[PathBuf], document_sizes: &BTreeMap<PathBuf, u64>) {
+    fn print_node(node: &TreeNode, depth: usize) {
+        for (name, child) in &node.children {
+            match child.size {
+                Some(size) => println!("{:indent$}{} ({})", "", name, HumanBytes(size), indent = depth * 2),
+                None => println!("{:indent$}{}/", "", name, indent = depth * 2),
             }
-            
-            // only download files that do not exist or are updated
-            let mut filtered_files = files.into_iter()
-            .filter(|f| !f.filepath.exists() || (updated(&f.filepath, &f.updated_at)) && options.download_newer)
-            .collect::<Vec<canvas::File>>();
-            
-            let mut lock = options.files_to_download.lock().await;
-            lock.append(&mut filtered_files);
-        },
-        Ok(canvas::FileResult::Err { status }) => {
-            let course_has_no_files = status == "unauthorized";
-            if !course_has_no_files {
-                println!("Failed to access files at link:{}, path:{}, status:{}", options.link, options.parent_folder_path.to_string_lossy(), status);
+            print_node(child, depth + 1);
+        }
+    }
+
+    let mut root = TreeNode::default();
+    let mut insert = |path: &std::path::Path, size: Option<u64>| {
+        let mut node = &mut root;
+        for component in path.strip_prefix(destination_folder).unwrap_or(path).components() {
+            node = node.children.entry(component.as_os_str().to_string_lossy().into_owned()).or_default();
+        }
+        node.size = size;
+    };
+    // courses without any files still show up
+    for course_folder_path in course_folder_paths {
+        insert(course_folder_path, None);
+    }
+    for file in files {
+        insert(&file.filepath, Some(file.size));
+    }
+    for (filepath, size) in document_sizes {
+        insert(filepath, Some(*size));
+    }
+    print_node(&root, 0);
+
+    let count = files.len() + document_sizes.len();
+    let total_size = files.iter().map(|file| file.size).sum::<u64>() + document_sizes.values().sum::<u64>();
+    println!("\n{} file{}, {} in total", count, if count == 1 { "" } else { "s" }, HumanBytes(total_size));
+}
+
+fn print_diff(destination_folder: &std::path::Path, files: &[canvas::File], course_folder_paths: &[PathBuf], document_paths: &HashSet<PathBuf>, options: &ProcessOptions) -> Result<()> {
+    let display_path = |path: &std::path::Path| path.strip_prefix(destination_folder).unwrap_or(path).to_string_lossy().into_owned();
+
+    let mut added = Vec::new();
+    let mut updated = Vec::new();
+    let mut unchanged = 0;
+    for file in files {
+        let metadata = match std::fs::metadata(&file.filepath) {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                added.push(file);
+                continue;
             }
+        };
+        // downloaded files have their modified time set to when they were updated on canvas
+        let newer_on_canvas = metadata.modified().is_ok_and(|modified| file.updated_at.is_some_and(|updated_at| modified < std::time::SystemTime::from(updated_at)));
+        if metadata.len() != file.size || newer_on_canvas {
+            updated.push(file);
+        } else {
+            unchanged += 1;
         }
-        Err(e) => {
-            println!("Failed to deserialize files at link:{}, path:{}\n{:?}", &options.link, &options.parent_folder_path.to_string_lossy(), e);
+    }
+
+    let canvas_paths = files.iter().map(|f| f.filepath.as_path()).collect::<HashSet<_>>();
+    let mut local_files = Vec::new();
+    for course_folder_path in course_folder_paths.iter().filter(|path| path.is_dir()) {
+        collect_files(course_folder_path, &mut local_files)?;
+    }
+    let mut deleted = local_files.into_iter()
+        .filter(|path| !canvas_paths.contains(path.as_path()) && !document_paths.contains(path))
+        .filter(|path| !canvas_ignored(options, path, false))
+        .collect::<Vec<_>>();
+    deleted.sort();
+
+    if !added.is_empty() {
+        println!("Added:");
+        for file in &added {
+            println!("  + {} ({} bytes)", display_path(&file.filepath), file.size);
         }
+    }
+    if !updated.is_empty() {
+        println!("Updated:");
+        for file in &updated {
+            println!("  ~ {} ({} bytes)", display_path(&file.filepath), file.size);
+        }
+    }
+    if !deleted.is_empty() {
+        println!("No longer on canvas:");
+        for path in &deleted {
+            println!("  - {}", display_path(path));
+        }
+    }
+    println!("{} added, {} updated, {} unchanged, {} no longer on canvas", added.len(), updated.len(), unchanged, deleted.len());
+    Ok(())
+}
+
+// the filepath starts with the course folder, so sorting by it groups files by course then folder
+// canvas can show the same file in several folders, only the first of its paths is downloaded
+fn link_duplicate_files(files: &[canvas::File], existing_files: &HashMap<u32, PathBuf>, duplicate_files: &[canvas::File]) {
+    let filepaths: HashMap<u32, &PathBuf> = existing_files.iter()
+        .map(|(id, filepath)| (*id, filepath))
+        .chain(files.iter().map(|file| (file.id, &file.filepath)))
+        .collect();
+    for duplicate_file in duplicate_files {
+        let filepath = match filepaths.get(&duplicate_file.id) {
+            Some(filepath) if filepath.exists() => filepath,
+            // the download failed, so there is nothing to link to
+            _ => continue,
+        };
+        // an older copy at the duplicate's path would make the link fail
+        if duplicate_file.filepath.exists() {
+            if let Err(e) = std::fs::remove_file(&duplicate_file.filepath) {
+                warning!("Failed to replace {} with a link\n{:?}", duplicate_file.filepath.to_string_lossy(), e);
+                continue;
+            }
+        }
+        match std::fs::hard_link(filepath, &duplicate_file.filepath) {
+            Ok(()) => status!("Linked {} to {}", duplicate_file.filepath.to_string_lossy(), filepath.to_string_lossy()),
+            Err(e) => warning!("Failed to link {} to {}\n{:?}", duplicate_file.filepath.to_string_lossy(), filepath.to_string_lossy(), e),
+        }
+    }
+}
+
+fn sort_files(files: &mut [canvas::File], sort: SortOrder) {
+    match sort {
+        SortOrder::Path => files.sort_by(|a, b| a.filepath.cmp(&b.filepath)),
+        SortOrder::Name => files.sort_by(|a, b| a.display_name.cmp(&b.display_name).then_with(|| a.filepath.cmp(&b.filepath))),
+        SortOrder::Size => files.sort_by(|a, b| a.size.cmp(&b.size).then_with(|| a.filepath.cmp(&b.filepath))),
+        SortOrder::Date => files.sort_by(|a, b| a.updated_at.cmp(&b.updated_at).then_with(|| a.filepath.cmp(&b.filepath))),
+    }
+}
+
+// expands a leading ~ or ~user, and $VAR or ${VAR} anywhere in the path
+fn expand_path(path: &std::path::Path) -> Result<PathBuf> {
+    // leave paths that are not valid unicode as they are
+    let path_str = match path.to_str() {
+        Some(path_str) => path_str,
+        None => return Ok(path.to_path_buf()),
+    };
+
+    let mut expanded = String::new();
+    let mut chars = path_str.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+
+        let braced = chars.next_if_eq(&'{').is_some();
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+            name.push(c);
+        }
+        if braced && chars.next_if_eq(&'}').is_none() {
+            return Err(anyhow::anyhow!("Unterminated ${{ in path: {}", path_str).context(ExitStatus::BadArguments));
+        }
+        if name.is_empty() {
+            // a lone $ is kept as is
+            expanded.push('$');
+            if braced {
+                expanded.push_str("{}");
+            }
+            continue;
+        }
+        let value = std::env::var(&name)
+            .with_context(|| format!("Environment variable {} used in path {} is not set", name, path_str))
+            .context(ExitStatus::BadArguments)?;
+        expanded.push_str(&value);
+    }
+
+    let tilde_path = match expanded.strip_prefix('~') {
+        Some(rest) => rest,
+        None => return Ok(PathBuf::from(expanded)),
+    };
+    let (user, rest) = match tilde_path.find(['/', std::path::MAIN_SEPARATOR]) {
+        Some(index) => tilde_path.split_at(index),
+        None => (tilde_path, ""),
+    };
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .context("Could not determine the home directory to expand ~")
+        .context(ExitStatus::BadArguments)?;
+    // home directories of other users are assumed to sit beside our own, e.g. /home/<user>
+    let home = if user.is_empty() {
+        home
+    } else {
+        home.parent().map(|parent| parent.join(user)).unwrap_or(home)
+    };
+    Ok(home.join(rest.trim_start_matches(['/', std::path::MAIN_SEPARATOR])))
+}
+
+#[cfg(test)]
+mod expand_path_tests {
+    use super::expand_path;
+    use std::path::{Path, PathBuf};
+
+    fn home() -> PathBuf {
+        std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")).map(PathBuf::from).unwrap()
+    }
+
+    #[test]
+    fn expands_a_leading_tilde() {
+        assert_eq!(expand_path(Path::new("~")).unwrap(), home());
+        assert_eq!(expand_path(Path::new("~/canvas/cs101")).unwrap(), home().join("canvas/cs101"));
+        assert_eq!(expand_path(Path::new("~alice/canvas")).unwrap(), home().parent().unwrap().join("alice").join("canvas"));
+    }
+
+    #[test]
+    fn leaves_a_tilde_elsewhere_in_the_path() {
+        assert_eq!(expand_path(Path::new("canvas/~backup")).unwrap(), PathBuf::from("canvas/~backup"));
+    }
+
+    #[test]
+    fn expands_plain_and_braced_variables() {
+        std::env::set_var("EXPAND_PATH_TEST_ROOT", "/mnt/nas");
+        std::env::set_var("EXPAND_PATH_TEST_TERM", "2024");
+
+        assert_eq!(expand_path(Path::new("$EXPAND_PATH_TEST_ROOT/canvas")).unwrap(), PathBuf::from("/mnt/nas/canvas"));
+        assert_eq!(expand_path(Path::new("/canvas/${EXPAND_PATH_TEST_TERM}_spring")).unwrap(), PathBuf::from("/canvas/2024_spring"));
+        assert_eq!(expand_path(Path::new("/canvas/cost$")).unwrap(), PathBuf::from("/canvas/cost$"));
+    }
+
+    #[test]
+    fn rejects_unset_variables_and_unterminated_braces() {
+        std::env::remove_var("EXPAND_PATH_TEST_UNSET");
+
+        assert!(expand_path(Path::new("$EXPAND_PATH_TEST_UNSET/canvas")).is_err());
+        assert!(expand_path(Path::new("${EXPAND_PATH_TEST_ROOT/canvas")).is_err());
+    }
+}
+
+// maps course ids to the folder they are downloaded to, as json or toml
+// e.g. {"12345": "/mnt/nas/cs101"} or 12345 = "/mnt/nas/cs101"
+fn load_course_map(path: &PathBuf) -> Result<HashMap<u32, PathBuf>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read course map: {}", path.to_string_lossy()))?;
+    let entries: HashMap<String, PathBuf> = if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("toml")) {
+        toml::from_str(&contents)
+            .with_context(|| format!("Course map is not valid toml: {}", path.to_string_lossy()))?
+    } else {
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Course map is not valid json: {}", path.to_string_lossy()))?
+    };
+
+    let mut course_map = HashMap::new();
+    for (course_id, course_folder_path) in entries {
+        let course_id = course_id.trim().parse::<u32>()
+            .with_context(|| format!("Course map key is not a course id: {}", course_id))?;
+        let course_folder_path = expand_path(&course_folder_path)?;
+        check_creatable(&course_folder_path)
+            .with_context(|| format!("Cannot create the folder for course {}: {}", course_id, course_folder_path.to_string_lossy()))?;
+        course_map.insert(course_id, course_folder_path);
+    }
+    Ok(course_map)
+}
+
+// looks at the closest folder that is already there, without writing anything, so previews can check the path too
+fn check_creatable(path: &std::path::Path) -> Result<()> {
+    let existing = path.ancestors()
+        .map(|ancestor| if ancestor.as_os_str().is_empty() { std::path::Path::new(".") } else { ancestor })
+        .find(|ancestor| ancestor.exists())
+        .context("None of the folders in the path exist")?;
+    let metadata = std::fs::metadata(existing)?;
+    if !metadata.is_dir() {
+        anyhow::bail!("{} is not a folder", existing.to_string_lossy());
+    }
+    if metadata.permissions().readonly() {
+        anyhow::bail!("{} is read only", existing.to_string_lossy());
+    }
+    Ok(())
+}
+
+// accepts any url within a course, e.g. https://canvas.example.com/courses/12345/pages/home
+fn parse_course_url(course_url: &str) -> Result<(String, u32)> {
+    let url = reqwest::Url::parse(course_url)
+        .with_context(|| format!("Invalid course url: {}", course_url))?;
+    let host = url.host_str()
+        .with_context(|| format!("Course url has no host: {}", course_url))?;
+
+    let mut segments = url.path_segments().into_iter().flatten();
+    let course_id = segments.find(|segment| *segment == "courses")
+        .and_then(|_| segments.next())
+        .and_then(|segment| segment.parse().ok())
+        .with_context(|| format!("Course url does not contain /courses/<id>: {}", course_url))?;
+
+    let canvas_url = match url.port() {
+        Some(port) => format!("{}://{}:{}", url.scheme(), host, port),
+        None => format!("{}://{}", url.scheme(), host),
+    };
+    Ok((canvas_url, course_id))
+}
+
+// lets the user tick the courses to download from a list, giving their ids
+fn pick_courses(courses: &[canvas::Course]) -> Result<Vec<u32>> {
+    let items: Vec<String> = courses.iter()
+        .map(|course| format!("{} - {}", course.course_code, course.name))
+        .collect();
+    let picked = dialoguer::MultiSelect::new()
+        .with_prompt("Pick the courses to download (space to toggle, enter to confirm)")
+        .items(&items)
+        .interact()
+        .context("Failed to show the course picker")?;
+    Ok(picked.into_iter().map(|i| courses[i].id).collect())
+}
+
+fn course_selected(selectors: &[String], course_ids: &[u32], course: &canvas::Course) -> bool {
+    if selectors.is_empty() && course_ids.is_empty() {
+        return true;
+    }
+    course_ids.contains(&course.id) || selectors.iter().any(|selector| {
+        let selector = selector.to_lowercase();
+        course.course_code.to_lowercase().contains(&selector) || course.name.to_lowercase().contains(&selector)
+    })
+}
+
+fn term_selected(terms: &[String], years: &[i32], course: &canvas::Course) -> bool {
+    let term_matches = terms.is_empty() || terms.iter().any(|selector| {
+        selector.parse::<u32>().is_ok_and(|term_id| course.enrollment_term_id == Some(term_id) || course.term.as_ref().is_some_and(|term| term.id == term_id))
+            || course.term.as_ref().is_some_and(|term| term.name.to_lowercase().contains(&selector.to_lowercase()))
+    });
+    // terms without a start date are often still named after their year, e.g. "2023 Fall"
+    let year_matches = years.is_empty() || course.term.as_ref().is_some_and(|term| {
+        match term.start_at.as_deref().and_then(|start_at| DateTime::parse_from_rfc3339(start_at).ok()) {
+            Some(start_at) => years.contains(&start_at.year()),
+            None => years.iter().any(|year| term.name.contains(&year.to_string())),
+        }
+    });
+    term_matches && year_matches
+}
+
+fn load_credential_file(path: &std::path::Path) -> Result<canvas::CredentialFile> {
+    if !path.exists() {
+        return Err(anyhow::anyhow!("The given path to the credentials file does not exist: {}", path.to_string_lossy())
+            .context(ExitStatus::BadArguments));
+    }
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open credential file: {}", path.to_string_lossy()))?;
+    let credential_file = serde_json::from_reader(file)
+        .with_context(|| format!("Credential file is not valid json: {}", path.to_string_lossy()))
+        .context(ExitStatus::BadArguments)?;
+    Ok(credential_file)
+}
+
+async fn fetch_user(canvas_client: &CanvasClient) -> Result<canvas::User> {
+    canvas_client.user().await.map_err(|e| {
+        match e.downcast_ref::<canvas::CanvasError>() {
+            Some(canvas_error) if canvas_error.is_unauthorized() => e.context("Authentication failed — check your token")
+                .context(ExitStatus::Authentication),
+            Some(canvas_error) => {
+                let context = format!("Failed to authenticate with {}, check that the canvas url and token are correct (status: {})", canvas_client.api_url(), canvas_error.status);
+                e.context(context).context(ExitStatus::Authentication)
+            },
+            None => e,
+        }
+    })
+}
+
+async fn fetch_course_nicknames(canvas_client: &CanvasClient) -> Result<HashMap<u32, String>> {
+    let nicknames = canvas_client.list_course_nicknames().await?;
+
+    Ok(nicknames.into_iter()
+        .map(|n| (n.course_id, n.nickname))
+        .collect())
+}
+
+// prefer the nickname, then the name picked with --folder-naming, then the course code, then the full name of the course
+fn course_folder_name(course: &canvas::Course, nickname: Option<&String>, folder_naming: FolderNaming) -> String {
+    let folder_name = match folder_naming {
+        FolderNaming::Code => course.course_code.clone(),
+        FolderNaming::Name => course.name.clone(),
+        FolderNaming::Both => format!("{} - {}", course.course_code, course.name),
+    };
+    for name in [nickname, Some(&folder_name), Some(&course.course_code), Some(&course.name)].into_iter().flatten() {
+        // section codes like CS101/L01 would otherwise lose their separator, running the two parts together
+        let name = sanitize_filename::sanitize(name.trim().replace(['/', '\\'], "_"));
+        if !name.trim().is_empty() {
+            return name;
+        }
+    }
+    // a name made up only of characters that are not allowed in paths would put the course straight into the destination folder
+    course.id.to_string()
+}
+
+// the .canvasignore in the destination folder takes precedence over one in the working directory
+fn load_canvas_ignore(destination_folder: &std::path::Path) -> Result<Option<Gitignore>> {
+    let path = match [destination_folder.join(".canvasignore"), PathBuf::from(".canvasignore")].into_iter().find(|path| path.is_file()) {
+        Some(path) => path,
+        None => return Ok(None),
     };
+    let mut builder = GitignoreBuilder::new("");
+    if let Some(e) = builder.add(&path) {
+        return Err(e).with_context(|| format!("Failed to read {}", path.to_string_lossy()));
+    }
+    let canvas_ignore = builder.build()
+        .with_context(|| format!("Invalid pattern in {}", path.to_string_lossy()))?;
+    Ok(Some(canvas_ignore))
 }
 
-#[derive(Parser)]
+fn build_glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).with_context(|| format!("Invalid glob: {}", glob))?);
+    }
+    Ok(builder.build()?)
+}
+
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    Success
+    1    Generic failure
+    2    Bad arguments
+    3    Authentication failure
+    4    Some files failed to download
+    5    Network or connectivity failure
+    6    Destination folder cannot be written to";
+
+#[derive(Clone, Parser)]
+#[clap(after_help = EXIT_CODES_HELP)]
 struct CommandLineOptions {
-    #[clap(short = 'u', long, forbid_empty_values = true)]
+    #[clap(short = 'u', long, env = "CANVAS_URL", forbid_empty_values = true)]
     canvas_url: Option<String>,
-    #[clap(short = 't', long, forbid_empty_values = true)]
+    /// Send api requests here instead of the canvas url, for institutions that serve the api from another domain
+    #[clap(long, forbid_empty_values = true)]
+    api_url: Option<String>,
+    #[clap(short = 't', long, env = "CANVAS_TOKEN", hide_env_values = true, forbid_empty_values = true)]
     canvas_token: Option<String>,
+    /// Read the canvas token from a file instead of passing it with -t
+    #[clap(long, parse(from_os_str), forbid_empty_values = true, conflicts_with = "token-stdin")]
+    token_file: Option<std::path::PathBuf>,
+    /// Read the canvas token from the first line of stdin instead of passing it with -t
+    #[clap(long, takes_value = false)]
+    token_stdin: bool,
+    /// Lay out the destination folder as a BagIt bag, with the files under data/ and a sha256 manifest
+    #[clap(long, takes_value = false, conflicts_with = "course-map")]
+    bagit: bool,
+    /// Only download files from folders with this name, or at this path within the course like Lectures/Week1 (repeatable)
+    #[clap(long, multiple_occurrences = true, forbid_empty_values = true)]
+    folder: Vec<String>,
+    /// Json or toml file mapping course ids to the folder each course is downloaded to
+    #[clap(long, parse(from_os_str))]
+    course_map: Option<std::path::PathBuf>,
+    /// Json file that keeps the download queue, with each file marked done as it finishes
+    #[clap(long, parse(from_os_str))]
+    queue_file: Option<std::path::PathBuf>,
+    /// Continue downloading the pending files in the queue file of an aborted run, without scanning the courses again
+    #[clap(long, takes_value = false, requires = "queue-file")]
+    resume: bool,
+    /// Print progress as lines of json events to stdout instead of showing progress bars, for other programs to read
+    #[clap(long, arg_enum, default_value = "text", conflicts_with_all = &["dry-run", "diff", "list", "courses-json", "interactive"])]
+    output_format: OutputFormat,
+    /// Print a completion script for this shell and exit, e.g. --generate-completions zsh > _canvas-downloader
+    #[clap(long, hide = true, arg_enum)]
+    generate_completions: Option<clap_complete::Shell>,
+    /// Pick the courses to download from a list, unless courses are already picked with --course, --course-id or --course-url
+    #[clap(long, takes_value = false)]
+    interactive: bool,
+    /// Only download courses whose code or name contains this text, ignoring case (repeatable)
+    #[clap(long, multiple_occurrences = true, forbid_empty_values = true)]
+    course: Vec<String>,
+    /// Only download courses in the enrollment term with this id, or whose name contains this text, ignoring case (repeatable)
+    #[clap(long, multiple_occurrences = true, forbid_empty_values = true)]
+    term: Vec<String>,
+    /// Only download courses in a term that started in this year (repeatable)
+    #[clap(long, multiple_occurrences = true)]
+    year: Vec<i32>,
+    /// Only download the folder with this id and its subfolders into a folder named after it, instead of any courses
+    #[clap(long, conflicts_with_all = &["course", "course-id", "course-url", "term", "year", "courses-json", "interactive", "resume"])]
+    folder_id: Option<u32>,
+    /// Only download the course with this id (repeatable)
+    #[clap(long, multiple_occurrences = true)]
+    course_id: Vec<u32>,
+    /// Only download files whose path from the course folder matches this glob, e.g. "*.pdf" (repeatable)
+    #[clap(long, multiple_occurrences = true, forbid_empty_values = true)]
+    include: Vec<String>,
+    /// Skip files whose path from the course folder matches this glob, e.g. "*.mp4", even when they match --include (repeatable)
+    #[clap(long, multiple_occurrences = true, forbid_empty_values = true)]
+    exclude: Vec<String>,
+    /// Only download the course at this url, e.g. https://canvas.example.com/courses/12345 (repeatable)
+    #[clap(long, multiple_occurrences = true, forbid_empty_values = true)]
+    course_url: Vec<String>,
     #[clap(short = 'c', long, parse(from_os_str), forbid_empty_values = true)]
     canvas_credential_path: Option<std::path::PathBuf>,
     #[clap(short = 'd', long, parse(from_os_str), default_value = ".")]
     destination_folder: std::path::PathBuf,
-    #[clap(short = 's', long, takes_value = false)]
+    #[clap(short = 's', long, takes_value = false, requires = "canvas-credential-path")]
     save_credentials: bool,
+    /// Only download from the profile with this name, when the credential file holds a list of profiles
+    #[clap(long, requires = "canvas-credential-path", conflicts_with = "save-credentials")]
+    profile: Option<String>,
+    /// Save the canvas token to the system keyring instead of the credential file
+    #[clap(long, takes_value = false, requires = "save-credentials")]
+    use_keyring: bool,
+    /// Set by the login subcommand
+    #[clap(skip)]
+    login: bool,
+    /// Only print warnings and errors, without the progress bars, e.g. for cron jobs
+    #[clap(short = 'q', long, takes_value = false, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print the usual messages but no progress bars, e.g. when the output goes to a log file
+    #[clap(long, takes_value = false)]
+    no_progress: bool,
+    /// Log why folders and files are skipped, -v for info and -vv for debug logs
+    #[clap(short = 'v', long, parse(from_occurrences))]
+    verbose: u8,
     #[clap(short = 'n', long, takes_value = false)]
     download_newer: bool,
+    /// Also download files that are hidden from students
+    #[clap(long, takes_value = false)]
+    include_hidden: bool,
+    /// Also download courses whose enrollment has concluded, instead of only the active ones
+    #[clap(long, takes_value = false)]
+    include_concluded: bool,
+    /// Also download unpublished and concluded courses that are hidden from the dashboard
+    #[clap(long, takes_value = false)]
+    include_hidden_courses: bool,
+    /// Save the front page of each course as home.html in the course folder
+    #[clap(long, takes_value = false)]
+    front_page: bool,
+    /// Convert the front page to markdown and save it as home.md instead, with --front-page
+    #[clap(long, takes_value = false)]
+    html_to_markdown: bool,
+    /// Save the syllabus of each course as syllabus.html in the course folder
+    #[clap(long, takes_value = false)]
+    syllabus: bool,
+    /// Print the courses as json and exit without downloading anything
+    #[clap(long, takes_value = false)]
+    courses_json: bool,
+    /// What to name course folders after, unless they have a nickname with --use-nicknames
+    #[clap(long, arg_enum, default_value = "code")]
+    folder_naming: FolderNaming,
+    /// Name course folders after the nicknames set in Canvas
+    #[clap(long, takes_value = false)]
+    use_nicknames: bool,
+    /// Stop queueing files once this many files have been found
+    #[clap(long)]
+    max_files: Option<usize>,
+    /// Start downloading files while still looking for more, with a single overall progress bar
+    #[clap(long, takes_value = false)]
+    parallel_scan_and_download: bool,
+    /// Also download the course's media objects, such as lecture recordings, into a Media folder
+    #[clap(long, takes_value = false)]
+    media: bool,
+    /// Quality of the media objects to download
+    #[clap(long, arg_enum, default_value = "high", requires = "media")]
+    media_quality: MediaQuality,
+    /// Also download the course's modules, with a folder for each module holding its files, pages and links
+    #[clap(long, takes_value = false)]
+    modules: bool,
+    /// Also save the course's pages as html files into a pages folder
+    #[clap(long, takes_value = false)]
+    pages: bool,
+    /// Also save the course's discussion topics as html files into a discussions folder, with the replies as json and the attached files
+    #[clap(long, takes_value = false)]
+    discussions: bool,
+    /// Also save the course's announcements as html files into an announcements folder, named after the date they were posted
+    #[clap(long, takes_value = false)]
+    announcements: bool,
+    /// List the files that would be added, updated or are no longer on canvas, then exit without downloading anything
+    #[clap(long, takes_value = false, conflicts_with_all = &["parallel-scan-and-download", "queue-file", "move-existing"])]
+    diff: bool,
+    /// Print the courses, folders and files on canvas as a tree with the file sizes, then exit without downloading anything
+    #[clap(long, takes_value = false, conflicts_with_all = &["parallel-scan-and-download", "queue-file", "move-existing", "diff", "dry-run"])]
+    list: bool,
+    /// List the files that would be downloaded with their sizes, then exit without downloading anything
+    #[clap(long, takes_value = false, conflicts_with_all = &["parallel-scan-and-download", "queue-file", "move-existing", "diff"])]
+    dry_run: bool,
+    /// Skip files larger than this, in bytes or with a unit like 500M or 2G
+    #[clap(long, parse(try_from_str = parse_size))]
+    max_file_size: Option<u64>,
+    /// Skip files smaller than this, in bytes or with a unit like 500M or 2G
+    #[clap(long, parse(try_from_str = parse_size))]
+    min_file_size: Option<u64>,
+    /// Only download files updated on canvas since this date (YYYY-MM-DD), or since the last successful run with "last"
+    #[clap(long, parse(try_from_str = parse_since))]
+    since: Option<Since>,
+    /// Put every file straight into its course folder, without the folders it is in on canvas
+    #[clap(long, takes_value = false, conflicts_with = "keep-root-folder")]
+    flatten: bool,
+    /// Put every file straight into the destination folder, without course folders or the folders it is in on canvas
+    #[clap(long, takes_value = false, conflicts_with = "keep-root-folder")]
+    flatten_all: bool,
+    /// Lay the course files out like this under the destination folder, e.g. "{term}/{course_code}/{folder_path}/{filename}",
+    /// with {course_code}, {course_name}, {term}, {folder_path} and {filename}
+    #[clap(long, parse(try_from_str), conflicts_with_all = &["flatten", "flatten-all", "course-map", "folder-id"])]
+    destination_template: Option<canvas::DestinationTemplate>,
+    /// Keep the course's root folder, e.g. "course files", instead of putting its contents straight into the course folder
+    #[clap(long, takes_value = false)]
+    keep_root_folder: bool,
+    /// Shorten folder and file names longer than this many characters, keeping the file extension
+    #[clap(long)]
+    max_path_length: Option<std::num::NonZeroUsize>,
+    /// Download every file again, overwriting the copies already on disk
+    #[clap(long, visible_alias = "overwrite", takes_value = false)]
+    force: bool,
+    /// Fail the run when a download does not match the md5 canvas has for it, instead of removing it with a warning
+    #[clap(long, takes_value = false)]
+    verify: bool,
+    /// Hard link files that canvas shows in several folders to the copy that was downloaded, instead of skipping them
+    #[clap(long, takes_value = false)]
+    link_duplicates: bool,
+    /// Order in which queued files are downloaded, ignored with --parallel-scan-and-download
+    #[clap(long, arg_enum, default_value = "path")]
+    sort: SortOrder,
+    /// Skip files listed in this json/csv index of file ids, and add downloaded files to it
+    #[clap(long, parse(from_os_str))]
+    seen_index: Option<std::path::PathBuf>,
+    /// Skip every file downloaded to the destination folder in an earlier run, even when it has been moved or deleted since,
+    /// by keeping a seen index in .canvas-seen.json in the destination folder
+    #[clap(long, takes_value = false, conflicts_with = "seen-index")]
+    only_new: bool,
+    /// Move files recorded in the seen index to their new path when the folder layout has changed
+    #[clap(long, takes_value = false, requires = "seen-index")]
+    move_existing: bool,
+    /// Maximum number of files downloaded at once, defaults to the number of cpus
+    #[clap(short = 'j', long)]
+    max_concurrent_downloads: Option<std::num::NonZeroUsize>,
+    /// Send every request through this http or socks5 proxy, e.g. socks5://127.0.0.1:1080, instead of the one in HTTPS_PROXY or ALL_PROXY
+    #[clap(long, parse(try_from_str = parse_proxy))]
+    proxy: Option<reqwest::Proxy>,
+    /// How many folders or files to ask canvas for with each request, up to 100
+    #[clap(long, default_value = "100")]
+    per_page: std::num::NonZeroU32,
+    /// Send at most this many requests a second, counting both the listing of folders and files and the downloads
+    #[clap(long)]
+    requests_per_second: Option<std::num::NonZeroU32>,
+    /// Seconds to wait for canvas to respond, or for more of a file to arrive, before retrying
+    #[clap(long, default_value = "30")]
+    timeout: std::num::NonZeroU64,
+    /// Number of times a request is sent again after a connection error or a 5xx/429 response
+    #[clap(long, default_value = "3")]
+    retries: u32,
+    /// Download partially downloaded files again from the start, instead of continuing where they stopped
+    #[clap(long, takes_value = false)]
+    no_resume: bool,
+    /// Limit the combined speed of every download to this many bytes a second, or with a unit like 500K or 2M
+    #[clap(long, parse(try_from_str = parse_size))]
+    max_speed: Option<u64>,
+    /// Maximum number of files downloaded at once from a single host
+    #[clap(long, default_value = "4")]
+    concurrency_per_host: std::num::NonZeroUsize,
+    /// Download files larger than 64 MiB over this many connections at once, when the host supports range requests
+    #[clap(long, visible_alias = "split-large-files", default_value = "1")]
+    segments: std::num::NonZeroUsize,
+    /// Remember the size and update time of downloaded files here, so unchanged files are skipped and changed files are downloaded again
+    #[clap(long, parse(from_os_str))]
+    state_file: Option<std::path::PathBuf>,
+    /// Stop sending requests to a host after this many failures in a row
+    #[clap(long, default_value = "5")]
+    circuit_breaker_failures: std::num::NonZeroUsize,
+    /// Seconds to wait before trying a host again after it stopped responding
+    #[clap(long, default_value = "60")]
+    circuit_breaker_cooldown: u64,
+    /// Write how long each file took to download, and its throughput, to this csv file
+    #[clap(long, parse(from_os_str))]
+    timings: Option<std::path::PathBuf>,
+    /// Write every file's id, name, path, size, url and whether it was downloaded, skipped or failed to this json or csv file
+    #[clap(long, parse(from_os_str))]
+    manifest: Option<std::path::PathBuf>,
+    /// Write the courses, folders and files that were walked, with their ids, urls, sizes and timestamps, to this json file
+    #[clap(long, parse(from_os_str))]
+    dump_metadata: Option<std::path::PathBuf>,
+}
+
+// clap already exits with 2 when it fails to parse the arguments
+#[derive(Clone, Copy, Debug)]
+enum ExitStatus {
+    Generic = 1,
+    BadArguments = 2,
+    Authentication = 3,
+    PartialDownload = 4,
+    Network = 5,
+    Destination = 6,
+}
+
+impl std::fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            ExitStatus::Generic => "failure",
+            ExitStatus::BadArguments => "bad arguments",
+            ExitStatus::Authentication => "authentication failure",
+            ExitStatus::PartialDownload => "some files failed to download",
+            ExitStatus::Network => "network failure",
+            ExitStatus::Destination => "the destination folder cannot be written to",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl std::error::Error for ExitStatus {}
+
+#[derive(Clone, Copy)]
+enum Since {
+    Date(NaiveDate),
+    /// When the last successful run into the destination folder started
+    Last,
+}
+
+// e.g. 500M or 1.5GB, the units are powers of 1024 like the sizes that are printed
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let number_end = size.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(size.len());
+    let (number, unit) = size.split_at(number_end);
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("expected a size like 500M or 2G, got {}", size)),
+    };
+    let number: f64 = number.parse().map_err(|_| format!("expected a size like 500M or 2G, got {}", size))?;
+    Ok((number * multiplier as f64) as u64)
+}
+
+// e.g. http://proxy.example.com:8080 or socks5://127.0.0.1:1080
+fn parse_proxy(proxy: &str) -> Result<reqwest::Proxy, String> {
+    let url = reqwest::Url::parse(proxy).map_err(|e| format!("expected a url like http://proxy.example.com:8080, got {} ({})", proxy, e))?;
+    if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+        return Err(format!("expected an http, https, socks5 or socks5h proxy, got {}", proxy));
+    }
+    reqwest::Proxy::all(url).map_err(|e| format!("invalid proxy {} ({})", proxy, e))
 }
 
-mod canvas {
-    use serde::{Deserialize, Serialize};
-    use std::sync::Arc;
-    use tokio::sync::Mutex;
-
-    #[derive(Clone, Deserialize, Serialize)]
-    #[serde(rename_all = "camelCase")]
-    pub struct Credentials {
-        pub canvas_url: String,
-        pub canvas_token: String,
-    }
-
-    #[derive(Deserialize)]
-    pub struct Course {
-        pub id: u32,
-        pub name: String,
-        pub course_code: String,
-    }
-
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    pub(crate) enum FolderResult {
-        Err { status: String },
-        Ok(Vec<Folder>),
-    }
-    
-    #[derive(Deserialize)]
-    pub struct Folder {
-        pub id: u32,
-        pub name: String,
-        pub folders_url: String,
-        pub files_url: String,
-        pub for_submissions: bool,
-        pub can_upload: bool,
-        pub parent_folder_id: Option<u32>,
-    }
-
-    #[derive(Deserialize)]
-    #[serde(untagged)]
-    pub(crate) enum FileResult {
-        Err { status: String },
-        Ok(Vec<File>),
-    }
-
-    #[derive(Clone, Debug, Deserialize)]
-    pub struct File {
-        pub id: u32,
-        pub folder_id: u32,
-        pub display_name: String,
-        pub size: u64,
-        pub url: String,
-        pub updated_at: String,
-        #[serde(skip)]
-        pub filepath: std::path::PathBuf,
-    }
-
-    #[derive(Clone)]
-    pub struct ProcessOptions {
-        pub canvas_token: String,
-        pub client: reqwest::Client,
-        pub link: String,
-        pub parent_folder_path: std::path::PathBuf,
-        pub files_to_download: Arc<Mutex<Vec<File>>>,
-        pub download_newer: bool,
+fn parse_since(since: &str) -> Result<Since, String> {
+    if since.eq_ignore_ascii_case("last") {
+        return Ok(Since::Last);
     }
+    NaiveDate::parse_from_str(since, "%Y-%m-%d")
+        .map(Since::Date)
+        .map_err(|_| format!("expected a date like 2024-01-31 or \"last\", got {}", since))
+}
+
+#[derive(clap::ArgEnum, Clone, Copy)]
+enum OutputFormat {
+    Text,
+    /// A line of json for every event, with the messages moved to stderr
+    Json,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy)]
+enum FolderNaming {
+    /// The course code, e.g. "CS2103T"
+    Code,
+    /// The course name, e.g. "Software Engineering"
+    Name,
+    /// Both, e.g. "CS2103T - Software Engineering"
+    Both,
+}
+
+#[derive(clap::ArgEnum, Clone, Copy)]
+enum SortOrder {
+    Path,
+    Name,
+    Size,
+    Date,
 }
@@ -0,0 +1,79 @@
+use crate::{canvas::{self, ProcessOptions}, traverse::canvas_ignored};
+use anyhow::Result;
+use futures::future::BoxFuture;
+use std::{collections::BTreeMap, path::PathBuf};
+use tracing::{debug, warn};
+
+/// Everything a provider needs to know about the course it is collecting from.
+pub struct CourseContext {
+    pub course: canvas::Course,
+    /// Folder the course's content is saved under
+    pub course_folder_path: PathBuf,
+    /// `{api_url}/api/v1/courses`
+    pub courses_link: String,
+    pub options: ProcessOptions,
+}
+
+/// Something a provider found that should end up on disk.
+pub enum DownloadItem {
+    /// A canvas file to download. When files are downloaded while scanning, the provider
+    /// also pushes it to `options.file_queue` as soon as it is found.
+    File(canvas::File),
+    /// Content that was already fetched while collecting, e.g. a rendered page
+    Document { filepath: PathBuf, contents: String },
+}
+
+/// A kind of course content, e.g. files or pages.
+///
+/// The run goes through every enabled provider for each course and downloads
+/// whatever they collect, so new content types only need a new implementation.
+pub trait ContentProvider: Send + Sync {
+    /// Short name used when reporting failures
+    fn name(&self) -> &'static str;
+
+    fn collect<'a>(&'a self, ctx: &'a CourseContext) -> BoxFuture<'a, Result<Vec<DownloadItem>>>;
+}
+
+/// Goes through the providers for the course, adding the files they collect to `options.files_to_download` and
+/// saving the documents. Gives back the size of each document left unsaved with `options.dry_run`.
+///
+/// `max_files` caps the files found over every course, rather than the files each provider collects.
+pub async fn collect_course(ctx: &mut CourseContext, providers: &[Box<dyn ContentProvider>], max_files: Option<usize>) -> BTreeMap<PathBuf, u64> {
+    let mut document_sizes = BTreeMap::new();
+    for provider in providers {
+        if let Some(max_files) = max_files {
+            ctx.options.max_files = Some(max_files.saturating_sub(ctx.options.files_to_download.lock().await.len()));
+        }
+        let items = match provider.collect(ctx).await {
+            Ok(items) => items,
+            Err(e) => {
+                warn!(course = %ctx.course.course_code, path = %ctx.course_folder_path.display(), error = ?e, "failed to collect {}", provider.name());
+                continue;
+            }
+        };
+        for item in items {
+            match item {
+                DownloadItem::File(canvas_file) => ctx.options.files_to_download.lock().await.push(canvas_file),
+                // pages and the like are skipped by the .canvasignore too, not only the files
+                DownloadItem::Document { filepath, .. } if canvas_ignored(&ctx.options, &filepath, false) => {
+                    debug!(path = %filepath.display(), "skipping {} matched by .canvasignore", provider.name());
+                },
+                DownloadItem::Document { filepath, contents } if ctx.options.dry_run => {
+                    document_sizes.insert(filepath, contents.len() as u64);
+                },
+                DownloadItem::Document { filepath, contents } => {
+                    // the course folder is left to the template to create
+                    if let Some(parent) = filepath.parent().filter(|parent| !parent.exists()) {
+                        if let Err(e) = std::fs::create_dir_all(parent) {
+                            warn!(folder = %parent.display(), error = ?e, "failed to create directory");
+                        }
+                    }
+                    if let Err(e) = std::fs::write(&filepath, contents) {
+                        warn!(path = %filepath.display(), error = ?e, "failed to save {}", provider.name());
+                    }
+                }
+            }
+        }
+    }
+    document_sizes
+}
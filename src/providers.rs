@@ -0,0 +1,494 @@
+use canvas_downloader::{canvas, traverse, ContentProvider, CourseContext, DownloadItem};
+use anyhow::{Context, Result};
+use futures::{future::BoxFuture, FutureExt};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+/// The course's files, walked folder by folder.
+pub struct FilesProvider;
+
+impl ContentProvider for FilesProvider {
+    fn name(&self) -> &'static str {
+        "files"
+    }
+
+    fn collect<'a>(&'a self, ctx: &'a CourseContext) -> BoxFuture<'a, Result<Vec<DownloadItem>>> {
+        async move {
+            let mut options = ctx.options.clone();
+            // this api gives us the root folder
+            options.link = format!("{}/{}/folders/by_path/", ctx.courses_link, ctx.course.id);
+            options.parent_folder_path = ctx.course_folder_path.clone();
+            // the course gets a list of its own, the queue is still shared with the workers
+            options.files_to_download = Arc::new(Mutex::new(Vec::new()));
+
+            traverse::process_folders(options.clone()).await;
+
+            let files = std::mem::take(&mut *options.files_to_download.lock().await);
+            Ok(files.into_iter().map(DownloadItem::File).collect())
+        }.boxed()
+    }
+}
+
+/// The course's front page, saved as `home.html`, or as `home.md` when `markdown` is set.
+pub struct FrontPageProvider {
+    pub markdown: bool,
+}
+
+impl ContentProvider for FrontPageProvider {
+    fn name(&self) -> &'static str {
+        "front page"
+    }
+
+    fn collect<'a>(&'a self, ctx: &'a CourseContext) -> BoxFuture<'a, Result<Vec<DownloadItem>>> {
+        async move {
+            // courses that land on modules or the syllabus have no front page to save
+            if ctx.course.default_view.as_deref() != Some("wiki") {
+                return Ok(Vec::new());
+            }
+
+            let link = format!("{}/{}/front_page", ctx.courses_link, ctx.course.id);
+            let resp = ctx.options.canvas_client.get(&link).await?;
+            // the course is set to show a page, but none has been picked as the front page
+            if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(Vec::new());
+            }
+
+            let page = canvas::CanvasError::check(resp).await?
+                .json::<canvas::Page>()
+                .await?;
+            let body = match page.body {
+                Some(body) => body,
+                None => return Ok(Vec::new()),
+            };
+
+            let (filename, contents) = if self.markdown {
+                ("home.md", page_markdown(&page.title, &body))
+            } else {
+                ("home.html", page_html(&page.title, &body))
+            };
+            Ok(vec![DownloadItem::Document {
+                filepath: ctx.course_folder_path.join(filename),
+                contents,
+            }])
+        }.boxed()
+    }
+}
+
+/// The course's syllabus, saved as `syllabus.html`.
+pub struct SyllabusProvider;
+
+impl ContentProvider for SyllabusProvider {
+    fn name(&self) -> &'static str {
+        "syllabus"
+    }
+
+    fn collect<'a>(&'a self, ctx: &'a CourseContext) -> BoxFuture<'a, Result<Vec<DownloadItem>>> {
+        async move {
+            let link = format!("{}/{}?include[]=syllabus_body", ctx.courses_link, ctx.course.id);
+            let resp = ctx.options.canvas_client.get(&link).await?;
+            let course = canvas::CanvasError::check(resp).await?
+                .json::<canvas::CourseDetails>()
+                .await?;
+            // most courses never fill in a syllabus
+            let body = match course.syllabus_body.filter(|body| !body.trim().is_empty()) {
+                Some(body) => body,
+                None => return Ok(Vec::new()),
+            };
+
+            Ok(vec![DownloadItem::Document {
+                filepath: ctx.course_folder_path.join("syllabus.html"),
+                contents: page_html(&format!("{} syllabus", ctx.course.name), &body),
+            }])
+        }.boxed()
+    }
+}
+
+#[derive(clap::ArgEnum, Clone, Copy)]
+pub enum MediaQuality {
+    High,
+    Low,
+}
+
+// media objects are identified by strings such as "m-4uHw2jDGLvzMHe", so their file id is taken from a hash of it.
+// That keeps the id the same between runs for the etags, state file and seen index, and the top bit being set
+// keeps it apart from the ids of canvas files
+fn media_file_id(media_id: &str) -> u32 {
+    let hash = Sha256::digest(media_id.as_bytes());
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) | 1 << 31
+}
+
+#[derive(Deserialize)]
+struct MediaObject {
+    media_id: String,
+    title: Option<String>,
+    user_entered_title: Option<String>,
+    created_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    media_sources: Vec<MediaSource>,
+}
+
+#[derive(Deserialize)]
+struct MediaSource {
+    url: String,
+    bitrate: Option<String>,
+    /// In kilobytes
+    size: Option<String>,
+    #[serde(rename = "fileExt")]
+    file_ext: Option<String>,
+}
+
+/// The course's media objects, such as lecture recordings, saved into a `Media` folder.
+pub struct MediaProvider {
+    pub quality: MediaQuality,
+}
+
+impl ContentProvider for MediaProvider {
+    fn name(&self) -> &'static str {
+        "media"
+    }
+
+    fn collect<'a>(&'a self, ctx: &'a CourseContext) -> BoxFuture<'a, Result<Vec<DownloadItem>>> {
+        async move {
+            let link = format!("{}/{}/media_objects", ctx.courses_link, ctx.course.id);
+            let media_objects = ctx.options.canvas_client.fetch_all_pages::<MediaObject>(&link).await?;
+
+            let media_folder_path = ctx.course_folder_path.join("Media");
+            let mut files = Vec::new();
+            for media_object in media_objects {
+                let bitrate = |source: &&MediaSource| source.bitrate.as_deref().and_then(|bitrate| bitrate.parse::<u64>().ok()).unwrap_or(0);
+                let source = match self.quality {
+                    MediaQuality::High => media_object.media_sources.iter().max_by_key(bitrate),
+                    MediaQuality::Low => media_object.media_sources.iter().min_by_key(bitrate),
+                };
+                let source = match source {
+                    Some(source) => source,
+                    None => continue,
+                };
+
+                let title = [&media_object.user_entered_title, &media_object.title]
+                    .into_iter()
+                    .flatten()
+                    .find(|title| !title.trim().is_empty())
+                    .unwrap_or(&media_object.media_id);
+                let display_name = match &source.file_ext {
+                    Some(file_ext) => format!("{}.{}", title.trim(), file_ext),
+                    None => title.trim().to_string(),
+                };
+                let filepath = media_folder_path.join(sanitize_filename::sanitize(&display_name));
+                // recordings do not change once uploaded, and the size canvas gives is only rounded to kilobytes,
+                // so one already on disk is taken to be up to date
+                let local_size = std::fs::metadata(&filepath).ok().map(|metadata| metadata.len());
+
+                files.push(canvas::File {
+                    id: media_file_id(&media_object.media_id),
+                    folder_id: 0,
+                    display_name,
+                    // a source without a size should still be fetched instead of being treated as empty
+                    size: local_size.unwrap_or_else(|| source.size.as_deref()
+                        .and_then(|size| size.parse::<u64>().ok())
+                        .map_or(1, |size| size.saturating_mul(1024).max(1))),
+                    url: source.url.clone(),
+                    updated_at: media_object.created_at,
+                    locked_for_user: false,
+                    hidden: false,
+                    md5: None,
+                    filepath,
+                });
+            }
+
+            let mut files = traverse::filter_files(&ctx.options, files);
+            if let Some(max_files) = ctx.options.max_files {
+                files.truncate(max_files);
+            }
+            if !files.is_empty() && !ctx.options.dry_run {
+                std::fs::create_dir_all(&media_folder_path)
+                    .with_context(|| format!("Failed to create directory: {}", media_folder_path.to_string_lossy()))?;
+            }
+            traverse::queue_files(&ctx.options, &files);
+            Ok(files.into_iter().map(DownloadItem::File).collect())
+        }.boxed()
+    }
+}
+
+/// The course's wiki pages, saved as html files into a `pages` folder.
+pub struct PagesProvider;
+
+impl ContentProvider for PagesProvider {
+    fn name(&self) -> &'static str {
+        "pages"
+    }
+
+    fn collect<'a>(&'a self, ctx: &'a CourseContext) -> BoxFuture<'a, Result<Vec<DownloadItem>>> {
+        async move {
+            let pages_link = format!("{}/{}/pages", ctx.courses_link, ctx.course.id);
+            // the list leaves out the body of each page, so every page is fetched on its own
+            let pages = ctx.options.canvas_client.fetch_all_pages::<canvas::Page>(&pages_link).await?;
+
+            let pages_folder_path = ctx.course_folder_path.join("pages");
+            let mut documents = Vec::new();
+            for page in pages {
+                let page_link = format!("{}/{}", pages_link, page.url);
+                let page = match fetch_json::<canvas::Page>(ctx, &page_link).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        warn!(page = %page.title, error = ?e, "failed to fetch page");
+                        continue;
+                    }
+                };
+                let body = match page.body {
+                    Some(body) => body,
+                    None => {
+                        warn!(page = %page.title, "skipping page without a body, it may be locked or unpublished");
+                        continue;
+                    }
+                };
+                documents.push(((page.page_id, format!("{}.html", page.title)), page_html(&page.title, &body)));
+            }
+            let (names, contents): (Vec<_>, Vec<_>) = documents.into_iter().unzip();
+            let items: Vec<_> = traverse::document_filepaths(&ctx.options, &pages_folder_path, &names).into_iter()
+                .zip(contents)
+                .map(|(filepath, contents)| DownloadItem::Document { filepath, contents })
+                .collect();
+            // the folders are created as the documents are saved, since --flatten or --destination-template can move them
+            Ok(items)
+        }.boxed()
+    }
+}
+
+#[derive(Deserialize)]
+struct Module {
+    id: u32,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct ModuleItem {
+    title: String,
+    #[serde(rename = "type")]
+    kind: String,
+    content_id: Option<u32>,
+    page_url: Option<String>,
+    external_url: Option<String>,
+}
+
+/// The course's modules, each saved into a folder of its own with the files, pages and links in it.
+pub struct ModulesProvider;
+
+impl ContentProvider for ModulesProvider {
+    fn name(&self) -> &'static str {
+        "modules"
+    }
+
+    fn collect<'a>(&'a self, ctx: &'a CourseContext) -> BoxFuture<'a, Result<Vec<DownloadItem>>> {
+        async move {
+            let modules_link = format!("{}/{}/modules", ctx.courses_link, ctx.course.id);
+            let modules = ctx.options.canvas_client.fetch_all_pages::<Module>(&modules_link).await?;
+
+            let mut files = Vec::new();
+            let mut documents = Vec::new();
+            for module in modules {
+                let module_folder_path = ctx.course_folder_path.join(sanitize_filename::sanitize(&module.name));
+                let items_link = format!("{}/{}/items", modules_link, module.id);
+                let module_items = ctx.options.canvas_client.fetch_all_pages::<ModuleItem>(&items_link).await?;
+
+                let mut module_files = Vec::new();
+                for module_item in module_items {
+                    match (module_item.kind.as_str(), module_item.content_id, module_item.page_url, module_item.external_url) {
+                        ("File", Some(file_id), _, _) => {
+                            let file_link = format!("{}/{}/files/{}", ctx.courses_link, ctx.course.id, file_id);
+                            match fetch_json::<canvas::File>(ctx, &file_link).await {
+                                Ok(mut file) => {
+                                    file.filepath = module_folder_path.join(sanitize_filename::sanitize(&file.display_name));
+                                    module_files.push(file);
+                                },
+                                // files can be locked until the module is unlocked, which should not hold up the rest
+                                Err(e) => warn!(module = %module.name, item = %module_item.title, error = ?e, "failed to fetch module file"),
+                            }
+                        },
+                        ("Page", _, Some(page_url), _) => {
+                            let page_link = format!("{}/{}/pages/{}", ctx.courses_link, ctx.course.id, page_url);
+                            match fetch_json::<canvas::Page>(ctx, &page_link).await {
+                                Ok(canvas::Page { title, body: Some(body), .. }) => documents.push(DownloadItem::Document {
+                                    filepath: module_folder_path.join(sanitize_filename::sanitize(format!("{}.html", title))),
+                                    contents: page_html(&title, &body),
+                                }),
+                                Ok(_) => {},
+                                Err(e) => warn!(module = %module.name, item = %module_item.title, error = ?e, "failed to fetch module page"),
+                            }
+                        },
+                        ("ExternalUrl", _, _, Some(external_url)) => documents.push(DownloadItem::Document {
+                            filepath: module_folder_path.join(sanitize_filename::sanitize(format!("{}.url", module_item.title))),
+                            contents: format!("[InternetShortcut]\nURL={}\n", external_url),
+                        }),
+                        // headers, quizzes and assignments have nothing to save
+                        _ => {},
+                    }
+                }
+
+                let module_files = traverse::filter_files(&ctx.options, module_files);
+                let has_documents = documents.iter().any(|document| matches!(document, DownloadItem::Document { filepath, .. } if filepath.starts_with(&module_folder_path)));
+                if (!module_files.is_empty() || has_documents) && !ctx.options.dry_run {
+                    std::fs::create_dir_all(&module_folder_path)
+                        .with_context(|| format!("Failed to create directory: {}", module_folder_path.to_string_lossy()))?;
+                }
+                files.extend(module_files);
+            }
+
+            if let Some(max_files) = ctx.options.max_files {
+                files.truncate(max_files);
+            }
+            traverse::queue_files(&ctx.options, &files);
+            Ok(files.into_iter().map(DownloadItem::File).chain(documents).collect())
+        }.boxed()
+    }
+}
+
+/// The course's discussion topics, saved as html files into a `discussions` folder, along with
+/// the replies as json and the files attached to the topic and its replies.
+pub struct DiscussionsProvider;
+
+impl ContentProvider for DiscussionsProvider {
+    fn name(&self) -> &'static str {
+        "discussions"
+    }
+
+    fn collect<'a>(&'a self, ctx: &'a CourseContext) -> BoxFuture<'a, Result<Vec<DownloadItem>>> {
+        async move {
+            let topics_link = format!("{}/{}/discussion_topics", ctx.courses_link, ctx.course.id);
+            let topics = ctx.options.canvas_client.fetch_all_pages::<canvas::DiscussionTopic>(&topics_link).await?;
+
+            let discussions_folder_path = ctx.course_folder_path.join("discussions");
+            let names: Vec<_> = topics.iter().map(|topic| (topic.id, format!("{}.html", topic.title))).collect();
+            let topic_filepaths = traverse::document_filepaths(&ctx.options, &discussions_folder_path, &names);
+            let mut files = Vec::new();
+            let mut documents = Vec::new();
+            for (topic, topic_filepath) in topics.into_iter().zip(topic_filepaths) {
+                // attachments go into a folder of their own, since several topics can attach files with the same name
+                let attachments_folder_path = topic_filepath.with_extension("");
+                let mut attachments = topic.attachments;
+
+                if topic.discussion_subentry_count > 0 {
+                    let view_link = format!("{}/{}/view", topics_link, topic.id);
+                    match fetch_json::<serde_json::Value>(ctx, &view_link).await {
+                        Ok(view) => {
+                            collect_attachments(&view["view"], &mut attachments);
+                            documents.push(DownloadItem::Document {
+                                filepath: topic_filepath.with_extension("json"),
+                                contents: serde_json::to_string_pretty(&view)?,
+                            });
+                        },
+                        // replies can be hidden until the student has posted, which should not hold up the topic itself
+                        Err(e) => warn!(topic = %topic.title, error = ?e, "failed to fetch discussion replies"),
+                    }
+                }
+
+                for mut attachment in attachments {
+                    attachment.filepath = attachments_folder_path.join(sanitize_filename::sanitize(&attachment.display_name));
+                    files.push(attachment);
+                }
+                documents.push(DownloadItem::Document {
+                    filepath: topic_filepath,
+                    contents: page_html(&topic.title, topic.message.as_deref().unwrap_or_default()),
+                });
+            }
+
+            let mut files = traverse::filter_files(&ctx.options, files);
+            if let Some(max_files) = ctx.options.max_files {
+                files.truncate(max_files);
+            }
+            if !ctx.options.dry_run {
+                let folders = files.iter()
+                    .filter_map(|file| file.filepath.parent())
+                    .chain(documents.iter().filter_map(|document| match document {
+                        DownloadItem::Document { filepath, .. } => filepath.parent(),
+                        DownloadItem::File(_) => None,
+                    }));
+                for folder in folders {
+                    std::fs::create_dir_all(folder)
+                        .with_context(|| format!("Failed to create directory: {}", folder.to_string_lossy()))?;
+                }
+            }
+            traverse::queue_files(&ctx.options, &files);
+            Ok(files.into_iter().map(DownloadItem::File).chain(documents).collect())
+        }.boxed()
+    }
+}
+
+/// The course's announcements, saved as html files named after the date they were posted into an `announcements` folder.
+pub struct AnnouncementsProvider;
+
+impl ContentProvider for AnnouncementsProvider {
+    fn name(&self) -> &'static str {
+        "announcements"
+    }
+
+    fn collect<'a>(&'a self, ctx: &'a CourseContext) -> BoxFuture<'a, Result<Vec<DownloadItem>>> {
+        async move {
+            // announcements are discussion topics that only the teachers can start
+            let announcements_link = format!("{}/{}/discussion_topics?only_announcements=true", ctx.courses_link, ctx.course.id);
+            let mut announcements = ctx.options.canvas_client.fetch_all_pages::<canvas::DiscussionTopic>(&announcements_link).await?;
+            announcements.sort_by_key(|announcement| announcement.posted_at);
+
+            let announcements_folder_path = ctx.course_folder_path.join("announcements");
+            let names: Vec<_> = announcements.iter()
+                .map(|announcement| match announcement.posted_at {
+                    Some(posted_at) => (announcement.id, format!("{} - {}.html", posted_at.format("%Y-%m-%d"), announcement.title)),
+                    None => (announcement.id, format!("{}.html", announcement.title)),
+                })
+                .collect();
+            let items: Vec<_> = traverse::document_filepaths(&ctx.options, &announcements_folder_path, &names).into_iter()
+                .zip(announcements)
+                .map(|(filepath, announcement)| DownloadItem::Document {
+                    filepath,
+                    contents: page_html(&announcement.title, announcement.message.as_deref().unwrap_or_default()),
+                })
+                .collect();
+            Ok(items)
+        }.boxed()
+    }
+}
+
+// a reply holds its attachment in `attachment`, or in `attachments` on newer instances,
+// and its own replies in `replies`
+fn collect_attachments(entries: &serde_json::Value, attachments: &mut Vec<canvas::File>) {
+    for entry in entries.as_array().into_iter().flatten() {
+        let entry_attachments = entry.get("attachments").and_then(|a| a.as_array()).into_iter().flatten()
+            .chain(entry.get("attachment").filter(|a| !a.is_null()));
+        for attachment in entry_attachments {
+            match serde_json::from_value::<canvas::File>(attachment.clone()) {
+                Ok(attachment) => attachments.push(attachment),
+                Err(e) => warn!(error = ?e, "skipping discussion attachment that could not be read"),
+            }
+        }
+        if let Some(replies) = entry.get("replies") {
+            collect_attachments(replies, attachments);
+        }
+    }
+}
+
+async fn fetch_json<T: serde::de::DeserializeOwned>(ctx: &CourseContext, link: &str) -> Result<T> {
+    let resp = ctx.options.canvas_client.get(link).await?;
+    canvas::CanvasError::check(resp).await?
+        .json::<T>()
+        .await
+        .with_context(|| format!("Failed to deserialize response from {}", link))
+}
+
+fn page_html(title: &str, body: &str) -> String {
+    format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n{}\n</body>\n</html>\n", escape_html(title), body)
+}
+
+// the title goes on top as a heading, since markdown has nowhere else to keep it
+fn page_markdown(title: &str, body: &str) -> String {
+    format!("# {}\n\n{}\n", title.trim(), html2md::parse_html(body).trim())
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
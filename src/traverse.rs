@@ -0,0 +1,505 @@
+use crate::{canvas::{self, Event, ProcessOptions}, download::long_path};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use std::{collections::{HashMap, HashSet}, path::PathBuf};
+use tracing::{debug, info, warn, Instrument};
+
+/// How many folder and file listings are fetched at once while looking for files
+pub const SCAN_CONCURRENCY: usize = 8;
+
+/// The options for walking the folders saved under `root_folder_path`, which is a course folder or a single folder
+pub fn traversal_options(options: &ProcessOptions, payload_folder: &std::path::Path, root_folder_path: &std::path::Path, flatten: bool, flatten_all: bool) -> ProcessOptions {
+    let mut options = options.clone();
+    // ignore patterns see the root folder as the first part of the path
+    options.ignore_root = root_folder_path.parent().map(PathBuf::from).unwrap_or_default();
+    options.flatten_folder = if flatten_all {
+        Some(payload_folder.to_path_buf())
+    } else if flatten {
+        Some(root_folder_path.to_path_buf())
+    } else {
+        None
+    };
+    options
+}
+
+// a filter without a slash matches a folder with that name anywhere in the course,
+// while a filter with slashes has to match the whole path from the course folder
+fn folder_matches(folder_filters: &[Vec<String>], relative_folder_path: &[String]) -> bool {
+    if folder_filters.is_empty() {
+        return true;
+    }
+    folder_filters.iter().any(|filter| match filter.as_slice() {
+        [name] => relative_folder_path.last().is_some_and(|folder_name| folder_name.eq_ignore_ascii_case(name)),
+        path => path.len() == relative_folder_path.len()
+            && path.iter().zip(relative_folder_path).all(|(a, b)| a.eq_ignore_ascii_case(b)),
+    })
+}
+
+fn folder_may_contain_match(folder_filters: &[Vec<String>], relative_folder_path: &[String]) -> bool {
+    folder_filters.iter().any(|filter| filter.len() == 1
+        || (filter.len() > relative_folder_path.len()
+            && filter.iter().zip(relative_folder_path).all(|(a, b)| a.eq_ignore_ascii_case(b))))
+}
+
+/// Whether the path is matched by the `.canvasignore`
+pub fn canvas_ignored(options: &ProcessOptions, path: &std::path::Path, is_dir: bool) -> bool {
+    let canvas_ignore = match &options.canvas_ignore {
+        Some(canvas_ignore) => canvas_ignore,
+        None => return false,
+    };
+    let relative_path = path.strip_prefix(&options.ignore_root).unwrap_or(path);
+    canvas_ignore.matched_path_or_any_parents(relative_path, is_dir).is_ignore()
+}
+
+/// Whether the path is picked by the include and exclude globs, the excludes winning over the includes
+pub fn glob_selected(options: &ProcessOptions, path: &std::path::Path) -> bool {
+    let relative_path = path.strip_prefix(&options.ignore_root).unwrap_or(path);
+    (options.include_globs.is_empty() || options.include_globs.is_match(relative_path))
+        && !options.exclude_globs.is_match(relative_path)
+}
+
+/// Walks the folders listed at `options.link`, adding the files found to `options.files_to_download`
+// async recursion needs boxing
+pub fn process_folders(options: ProcessOptions) -> BoxFuture<'static, ()> {
+    let span = tracing::info_span!(parent: None, "folders", link = %options.link, path = %options.parent_folder_path.display());
+    async move {
+        // no need to keep walking once the queue is full
+        if let Some(max_files) = options.max_files {
+            if options.files_to_download.lock().await.len() >= max_files {
+                debug!("already found --max-files files, not listing folders");
+                return;
+            }
+        }
+
+        let folders_result = {
+            let _permit = scan_permit(&options).await;
+            options.canvas_client.fetch_all_pages::<canvas::Folder>(&options.link).await
+        };
+        
+        match folders_result {
+            Ok(folders) => {
+                let mut subfolders = Vec::new();
+                for folder in folders {
+                    let is_root = folder.parent_folder_id.is_none();
+                    subfolders.extend(process_folder(&options, folder, is_root));
+                }
+                walk_folders(&options, subfolders).await;
+            },
+            Err(e) => match e.downcast_ref::<canvas::CanvasError>() {
+                // courses with no folders are reported as unauthorized
+                Some(canvas_error) if canvas_error.is_unauthorized() => {
+                    debug!("no access to the folders");
+                },
+                Some(canvas_error) => {
+                    warn!(error = %canvas_error, "failed to access folders");
+                },
+                None => {
+                    warn!(error = ?e, "failed to list folders");
+                },
+            },
+        }
+    }.instrument(span).boxed()
+}
+
+/// Lists the folder's files and subfolders, `is_root` folders put their contents straight into the parent folder
+pub fn process_folder(options: &ProcessOptions, folder: canvas::Folder, is_root: bool) -> Vec<BoxFuture<'static, ()>> {
+    let mut subfolders: Vec<BoxFuture<'static, ()>> = Vec::new();
+    let sanitized_folder_name = sanitize_filename::sanitize(&folder.name);
+    // the root folder of a course has no parent
+    // so we avoid the extra directory nesting by not appending the root folder name
+    // unless asked to, and --folder paths start below it either way
+    let mut relative_folder_path = options.relative_folder_path.clone();
+    let folder_path = if !is_root {
+        relative_folder_path.push(sanitized_folder_name.clone());
+        options.parent_folder_path.clone().join(truncate_name(sanitized_folder_name, options.max_path_length))
+    } else if options.keep_root_folder {
+        options.parent_folder_path.clone().join(truncate_name(sanitized_folder_name, options.max_path_length))
+    } else {
+        options.parent_folder_path.clone()
+    };
+
+    if canvas_ignored(options, &folder_path, true) {
+        debug!(folder = %folder_path.display(), "skipping folder matched by .canvasignore");
+        return subfolders;
+    }
+    if let Some(traversed) = &options.traversed {
+        traversed.lock().unwrap().folders.push(folder.clone());
+    }
+
+    let folder_selected = options.folder_selected || folder_matches(&options.folder_filters, &relative_folder_path);
+    if folder_selected {
+        // the files are still queued, so they are reported as failed downloads
+        if !options.dry_run && options.flatten_folder.is_none() && options.destination_template.is_none() && !long_path(&folder_path).exists() {
+            if let Err(e) = std::fs::create_dir_all(long_path(&folder_path)) {
+                warn!(folder = %folder_path.display(), error = %e, "failed to create directory");
+            }
+        }
+
+        let mut new_options = options.clone();
+        new_options.link = folder.files_url.clone();
+        new_options.parent_folder_path = folder_path.clone();
+        subfolders.push(process_files(new_options).boxed());
+    }
+
+    if folder_selected || folder_may_contain_match(&options.folder_filters, &relative_folder_path) {
+        let mut new_options = options.clone();
+        new_options.link = folder.folders_url.clone();
+        new_options.parent_folder_path = folder_path.clone();
+        new_options.relative_folder_path = relative_folder_path;
+        new_options.folder_selected = folder_selected;
+        subfolders.push(process_folders(new_options));
+    } else {
+        debug!(folder = %folder_path.display(), "skipping folder outside of --folder");
+    }
+    subfolders
+}
+
+/// Waits for the folders given by [`process_folder`] to be walked
+pub async fn walk_folders(options: &ProcessOptions, subfolders: Vec<BoxFuture<'static, ()>>) {
+    // the folders are listed at the same time, with the permits keeping the number of requests in check
+    if options.scan_permits.is_some() {
+        futures::future::join_all(subfolders).await;
+    } else {
+        for subfolder in subfolders {
+            subfolder.await;
+        }
+    }
+}
+
+// paths are compared ignoring case, as they would clash on windows and macos
+fn path_key(path: &std::path::Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+// paths recorded by earlier runs belong to the file they were recorded for
+fn recorded_paths(options: &ProcessOptions) -> HashMap<String, u32> {
+    options.file_states.values()
+        .map(|file_state| (path_key(&file_state.filepath), file_state.id))
+        .chain(options.seen_files.iter().filter_map(|(id, filepath)| filepath.as_ref().map(|filepath| (path_key(filepath), *id))))
+        .collect()
+}
+
+// the path for a name in folder_path, shortened and laid out by --destination-template, with the id added when the path is taken
+fn claimable_filepath(options: &ProcessOptions, taken_paths: &[&HashMap<String, u32>], folder_path: &std::path::Path, name: &str, id: u32) -> PathBuf {
+    let sanitized_filename = sanitize_filename::sanitize(name);
+    let join = |filename: String| match &options.destination_template {
+        Some(destination_template) => options.destination_folder.join(destination_template.expand(&options.template_values, folder_path, &filename)),
+        None => folder_path.join(filename),
+    };
+    let filepath = join(truncate_name(sanitized_filename.clone(), options.max_path_length));
+    let key = path_key(&filepath);
+    if !taken_paths.iter().any(|paths| matches!(paths.get(&key), Some(taken_id) if *taken_id != id)) {
+        return filepath;
+    }
+    // the name is shortened before the id is added, so the id is never cut off
+    let id_length = filename_with_id("", id).chars().count();
+    let max_length = options.max_path_length.map(|max_length| max_length.saturating_sub(id_length).max(1));
+    let filepath = join(filename_with_id(&truncate_name(sanitized_filename, max_length), id));
+    debug!(path = %filepath.display(), id, "another file has the same name, adding the id");
+    filepath
+}
+
+/// Claims paths for documents a provider saves into `folder_path`, e.g. pages named after their title, the same way as
+/// the files in a folder: the document with the lowest id keeps the plain name, and the others get their id added.
+pub fn document_filepaths(options: &ProcessOptions, folder_path: &std::path::Path, documents: &[(u32, String)]) -> Vec<PathBuf> {
+    let recorded_paths = recorded_paths(options);
+    let mut claimed_paths = options.claimed_paths.lock().unwrap();
+    // documents are put straight into the course or destination folder with --flatten, like files
+    let folder_path = options.flatten_folder.as_deref().unwrap_or(folder_path);
+
+    let mut filepaths = vec![PathBuf::new(); documents.len()];
+    let mut order: Vec<usize> = (0..documents.len()).collect();
+    order.sort_by_key(|&i| documents[i].0);
+    for i in order {
+        let (id, name) = &documents[i];
+        let filepath = claimable_filepath(options, &[&claimed_paths, &recorded_paths], folder_path, name, *id);
+        claimed_paths.insert(path_key(&filepath), *id);
+        filepaths[i] = filepath;
+    }
+    filepaths
+}
+
+// canvas tells files apart by id, so several files in a folder can have the same name
+fn assign_filepaths(options: &ProcessOptions, files: &mut [canvas::File]) {
+    let recorded_paths = recorded_paths(options);
+    let mut claimed_paths = options.claimed_paths.lock().unwrap();
+    // files are put straight into the course or destination folder with --flatten
+    let folder_path = options.flatten_folder.as_ref().unwrap_or(&options.parent_folder_path);
+
+    // the file with the lowest id keeps the plain name, so names stay the same from run to run
+    let mut order: Vec<usize> = (0..files.len()).collect();
+    order.sort_by_key(|&i| files[i].id);
+    for i in order {
+        let file = &mut files[i];
+        let mut filepath = claimable_filepath(options, &[&claimed_paths, &recorded_paths], folder_path, &file.display_name, file.id);
+        if let Some(renamed_filepath) = renamed_filepath(&filepath, file.size) {
+            filepath = renamed_filepath;
+        }
+        claimed_paths.insert(path_key(&filepath), file.id);
+        if options.destination_template.is_some() && !options.dry_run {
+            if let Some(parent) = filepath.parent().filter(|parent| !long_path(parent).exists()) {
+                if let Err(e) = std::fs::create_dir_all(long_path(parent)) {
+                    warn!(folder = %parent.display(), error = %e, "failed to create directory");
+                }
+            }
+        }
+        file.filepath = filepath;
+    }
+}
+
+// a file without an extension may have been given the one from its Content-Disposition by an earlier run
+fn renamed_filepath(filepath: &std::path::Path, size: u64) -> Option<PathBuf> {
+    if filepath.extension().is_some() || filepath.exists() {
+        return None;
+    }
+    let filename = filepath.file_name()?;
+    std::fs::read_dir(filepath.parent()?).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some()
+            && path.file_stem() == Some(filename)
+            && std::fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.len() == size))
+}
+
+/// Shortens the name to `max_length` characters, keeping the extension so the file still opens with the right program
+pub fn truncate_name(name: String, max_length: Option<usize>) -> String {
+    let max_length = match max_length {
+        Some(max_length) if name.chars().count() > max_length => max_length,
+        _ => return name,
+    };
+    // windows does not allow names that end in a space or a dot
+    let shorten = |name: &str, length: usize| {
+        let shortened = name.chars().take(length).collect::<String>();
+        match shortened.trim_end_matches([' ', '.']) {
+            "" => shortened.clone(),
+            trimmed => trimmed.to_string(),
+        }
+    };
+    let truncated = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.chars().count() + 1 < max_length => {
+            format!("{}.{}", shorten(stem, max_length - extension.chars().count() - 1), extension)
+        },
+        _ => shorten(&name, max_length),
+    };
+    warn!(name, truncated, "name is longer than --max-path-length, shortening it");
+    truncated
+}
+
+// e.g. notes.pdf becomes notes (1234).pdf
+fn filename_with_id(filename: &str, id: u32) -> String {
+    let path = std::path::Path::new(filename);
+    match (path.file_stem(), path.extension()) {
+        (Some(stem), Some(extension)) => format!("{} ({}).{}", stem.to_string_lossy(), id, extension.to_string_lossy()),
+        _ => format!("{} ({})", filename, id),
+    }
+}
+
+// when the folder layout changes, files from a previous run can be moved rather than downloaded again
+async fn move_existing_files(options: &ProcessOptions, files: &[canvas::File]) {
+    for file in files {
+        let old_filepath = match options.seen_files.get(&file.id) {
+            Some(Some(old_filepath)) => old_filepath,
+            _ => continue,
+        };
+        if *old_filepath == file.filepath || !old_filepath.exists() || file.filepath.exists() {
+            continue;
+        }
+
+        let moved = (|| -> Result<()> {
+            if let Some(parent) = file.filepath.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(old_filepath, &file.filepath)?;
+            Ok(())
+        })();
+        match moved {
+            Ok(_) => {
+                info!(from = %old_filepath.display(), to = %file.filepath.display(), "moved file");
+                options.moved_files.lock().await.push(canvas::SeenFile { id: file.id, filepath: Some(file.filepath.clone()) });
+            },
+            Err(e) => {
+                warn!(from = %old_filepath.display(), to = %file.filepath.display(), error = ?e, "failed to move file");
+            }
+        }
+    }
+}
+
+async fn process_files(options: ProcessOptions) {
+    let span = tracing::info_span!(parent: None, "files", link = %options.link, path = %options.parent_folder_path.display());
+    process_files_in_span(options).instrument(span).await
+}
+
+fn skip_existing(options: &ProcessOptions, file: &canvas::File) {
+    options.existing_files.lock().unwrap().entry(file.id).or_insert_with(|| file.filepath.clone());
+    if let Some(skipped_files) = &options.skipped_files {
+        skipped_files.lock().unwrap().push(file.clone());
+    }
+}
+
+/// Only keeps the files that do not exist or are updated, and are not left out by the options
+pub fn filter_files(options: &ProcessOptions, files: Vec<canvas::File>) -> Vec<canvas::File> {
+    fn updated(filepath: &PathBuf, new_modified: DateTime<Utc>) -> bool {
+        (|| -> Result<bool> {
+            let old_modified = std::fs::metadata(filepath)?.modified()?;
+            let new_modified = std::time::SystemTime::from(new_modified);
+            let updated = old_modified < new_modified;
+            if updated {
+                warn!("Found update for {filepath:?}. Use -n to download updated files.");
+            }
+            Ok(updated)
+        })().unwrap_or(false)
+    }
+
+    files.into_iter()
+        // there is nothing to download yet, or any more
+        .filter(|f| {
+            let has_url = !f.url.trim().is_empty();
+            if !has_url {
+                warn!(file = %f.display_name, "skipping file without a download url, it may still be uploading or have been deleted");
+            }
+            has_url
+        })
+        // canvas answers with a 403 for these, so there is no point in asking
+        .filter(|f| {
+            if f.locked_for_user {
+                info!(file = %f.display_name, "skipping file that is locked");
+            }
+            !f.locked_for_user
+        })
+        .filter(|f| {
+            let included = !f.hidden || options.include_hidden;
+            if !included {
+                debug!(file = %f.display_name, "skipping hidden file, use --include-hidden to download it");
+            }
+            included
+        })
+        .filter(|f| {
+            let selected = !canvas_ignored(options, &f.filepath, false) && glob_selected(options, &f.filepath);
+            if !selected {
+                debug!(file = %f.display_name, "skipping file matched by .canvasignore or --include/--exclude");
+            }
+            selected
+        })
+        .filter(|f| {
+            let within_limits = options.max_file_size.is_none_or(|max_file_size| f.size <= max_file_size)
+                && options.min_file_size.is_none_or(|min_file_size| f.size >= min_file_size);
+            if !within_limits {
+                debug!(file = %f.display_name, size = f.size, "skipping file outside of --max-file-size/--min-file-size");
+                let mut size_filtered_files = options.size_filtered_files.lock().unwrap();
+                size_filtered_files.count += 1;
+                size_filtered_files.bytes += f.size;
+            }
+            within_limits
+        })
+        // files canvas gives no updated_at for could have changed at any time
+        .filter(|f| {
+            let recent = options.since.is_none_or(|since| f.updated_at.is_none_or(|updated_at| updated_at >= since));
+            if !recent {
+                debug!(file = %f.display_name, "skipping file last updated before --since");
+            }
+            recent
+        })
+        // a diff compares every file on canvas with the local copy
+        .filter(|f| {
+            let unseen = options.diff || !options.seen_files.contains_key(&f.id);
+            if !unseen {
+                debug!(file = %f.display_name, "skipping file in the seen index");
+                skip_existing(options, f);
+            }
+            unseen
+        })
+        .filter(|f| {
+            let changed = options.diff || options.force || match options.file_states.get(&f.id) {
+                // the state from the last run tells us whether the file changed without looking at the local copy
+                Some(state) if state.filepath == f.filepath && f.filepath.exists() => {
+                    state.size != f.size || state.updated_at != f.updated_at
+                },
+                _ => !f.filepath.exists() || f.updated_at.is_some_and(|updated_at| updated(&f.filepath, updated_at)) && options.download_newer
+                    // a size that does not match is what is left of an interrupted download, or a file that changed on canvas
+                    || std::fs::metadata(&f.filepath).is_ok_and(|metadata| metadata.len() != f.size),
+            };
+            if !changed {
+                debug!(file = %f.display_name, "skipping file that is up to date");
+                skip_existing(options, f);
+            }
+            changed
+        })
+        .collect()
+}
+
+/// Leaves out the files found in more than one folder, or already downloaded, giving back the ones left out
+pub fn dedupe_files(files: &mut Vec<canvas::File>, existing_files: &HashMap<u32, PathBuf>) -> Vec<canvas::File> {
+    let mut file_ids: HashSet<u32> = existing_files.keys().copied().collect();
+    // folders are listed at the same time, so the order they are found in is not the same from run to run
+    files.sort_by(|a, b| a.filepath.cmp(&b.filepath));
+    let (unique_files, duplicate_files) = std::mem::take(files).into_iter().partition(|file| file_ids.insert(file.id));
+    *files = unique_files;
+    for file in &duplicate_files {
+        debug!(path = %file.filepath.to_string_lossy(), id = file.id, "skipping file already found in another folder");
+    }
+    duplicate_files
+}
+
+/// Hands the files to the download workers straight away with `--parallel-scan-and-download`
+pub fn queue_files(options: &ProcessOptions, files: &[canvas::File]) {
+    if let Some(file_queue) = &options.file_queue {
+        for file in files {
+            if file_queue.push(file.clone()) {
+                if let Some(on_event) = &options.on_event {
+                    on_event(&Event::Discovered { file, path: &file.filepath });
+                }
+            }
+        }
+    }
+}
+
+// only held while a listing is fetched, so folders waiting on their subfolders do not hold up the rest
+async fn scan_permit(options: &ProcessOptions) -> Option<tokio::sync::SemaphorePermit<'_>> {
+    match &options.scan_permits {
+        Some(scan_permits) => scan_permits.acquire().await.ok(),
+        None => None,
+    }
+}
+
+async fn process_files_in_span(options: ProcessOptions) {
+    let files_result = {
+        let _permit = scan_permit(&options).await;
+        options.canvas_client.fetch_all_pages::<canvas::File>(&options.link).await
+    };
+
+    match files_result {
+        Ok(mut files) => {
+            assign_filepaths(&options, &mut files);
+            if let Some(traversed) = &options.traversed {
+                traversed.lock().unwrap().files.extend(files.iter().cloned());
+            }
+
+            if options.move_existing && !options.dry_run {
+                move_existing_files(&options, &files).await;
+            }
+            
+            let mut filtered_files = filter_files(&options, files);
+            
+            let mut lock = options.files_to_download.lock().await;
+            if let Some(max_files) = options.max_files {
+                filtered_files.truncate(max_files.saturating_sub(lock.len()));
+            }
+            info!(files = filtered_files.len(), "found files to download");
+            queue_files(&options, &filtered_files);
+            lock.append(&mut filtered_files);
+        },
+        Err(e) => match e.downcast_ref::<canvas::CanvasError>() {
+            // courses with no files are reported as unauthorized
+            Some(canvas_error) if canvas_error.is_unauthorized() => {
+                debug!("no access to the files");
+            },
+            Some(canvas_error) => {
+                warn!(error = %canvas_error, "failed to access files");
+            },
+            None => {
+                warn!(error = ?e, "failed to list files");
+            },
+        },
+    };
+}
@@ -0,0 +1,680 @@
+use assert_cmd::Command;
+use serde_json::json;
+use std::path::Path;
+use wiremock::matchers::{header, header_exists, method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+async fn mount_json(server: &MockServer, link: &str, body: serde_json::Value) {
+    Mock::given(method("GET"))
+        .and(path(link))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(server)
+        .await;
+}
+
+fn folder_json(server: &MockServer, id: u32, name: &str, parent_folder_id: Option<u32>) -> serde_json::Value {
+    json!({
+        "id": id,
+        "name": name,
+        "folders_url": format!("{}/api/v1/folders/{}/folders", server.uri(), id),
+        "files_url": format!("{}/api/v1/folders/{}/files", server.uri(), id),
+        "for_submissions": false,
+        "can_upload": false,
+        "parent_folder_id": parent_folder_id,
+    })
+}
+
+fn file_json(server: &MockServer, id: u32, folder_id: u32, display_name: &str) -> serde_json::Value {
+    json!({
+        "id": id,
+        "folder_id": folder_id,
+        "display_name": display_name,
+        "size": 3,
+        "url": format!("{}/files/{}/download", server.uri(), id),
+        "updated_at": "2024-02-01T10:00:00Z",
+    })
+}
+
+// a course with syllabus.pdf in its root folder, and a.pdf two folders down
+async fn mount_course(server: &MockServer) {
+    mount_json(server, "/api/v1/users/self", json!({ "id": 1, "name": "Test User" })).await;
+    mount_json(server, "/api/v1/courses", json!([{ "id": 1, "name": "Intro to CS", "course_code": "CS101", "enrollments": [{}] }])).await;
+    mount_json(server, "/api/v1/courses/1/folders/by_path/", json!([folder_json(server, 10, "course files", None)])).await;
+    mount_json(server, "/api/v1/folders/10/folders", json!([folder_json(server, 11, "Week 1: Intro?", Some(10))])).await;
+    mount_json(server, "/api/v1/folders/11/folders", json!([folder_json(server, 12, "Slides*", Some(11))])).await;
+    mount_json(server, "/api/v1/folders/12/folders", json!([])).await;
+    mount_json(server, "/api/v1/folders/10/files", json!([file_json(server, 100, 10, "syllabus.pdf")])).await;
+    mount_json(server, "/api/v1/folders/11/files", json!([])).await;
+    mount_json(server, "/api/v1/folders/12/files", json!([file_json(server, 120, 12, "a<b>.pdf")])).await;
+}
+
+async fn mount_download(server: &MockServer, id: u32, expected_requests: u64) {
+    for request_method in ["HEAD", "GET"] {
+        Mock::given(method(request_method))
+            .and(path(format!("/files/{}/download", id)))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"abc".to_vec()))
+            .expect(expected_requests)
+            .mount(server)
+            .await;
+    }
+}
+
+async fn run(server: &MockServer, working_folder: &Path, args: &[&str]) -> std::process::Output {
+    tokio::process::Command::new(env!("CARGO_BIN_EXE_canvas-downloader"))
+        .args(["-u", &server.uri(), "-t", "token"])
+        .args(args)
+        // keeps the config file and .canvasignore of whoever runs the tests out of it
+        .current_dir(working_folder)
+        .env("XDG_CONFIG_HOME", working_folder)
+        .output()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn sanitizes_the_names_of_nested_folders() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let course_folder = working_folder.path().join("downloads").join("CS101");
+    assert_eq!(std::fs::read(course_folder.join("syllabus.pdf")).unwrap(), b"abc");
+    assert_eq!(std::fs::read(course_folder.join("Week 1 Intro").join("Slides").join("ab.pdf")).unwrap(), b"abc");
+    // nothing asked for --since, so there is no last sync to record
+    assert!(!working_folder.path().join("downloads/.last-sync").exists());
+}
+
+#[tokio::test]
+async fn skips_files_that_are_already_downloaded() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 0).await;
+    mount_download(&server, 120, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+    let course_folder = working_folder.path().join("downloads").join("CS101");
+    std::fs::create_dir_all(&course_folder).unwrap();
+    // written after the file was updated on canvas, with the same size
+    std::fs::write(course_folder.join("syllabus.pdf"), b"old").unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(course_folder.join("syllabus.pdf")).unwrap(), b"old");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Downloaded 1 file (3 B)"), "{}", String::from_utf8_lossy(&output.stdout));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("skipped 1 already downloaded"));
+}
+
+#[tokio::test]
+async fn creates_a_nested_destination_folder() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads/2024/spring"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(working_folder.path().join("downloads/2024/spring/CS101/syllabus.pdf").is_file());
+}
+
+#[tokio::test]
+async fn saves_discussions_with_their_replies_and_attachments() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    mount_download(&server, 130, 1).await;
+    mount_json(&server, "/api/v1/courses/1/discussion_topics", json!([
+        { "id": 5, "title": "Week 1: Questions", "message": "<p>Ask here</p>", "attachments": [], "discussion_subentry_count": 1 },
+    ])).await;
+    mount_json(&server, "/api/v1/courses/1/discussion_topics/5/view", json!({
+        "participants": [],
+        "view": [{ "id": 50, "message": "<p>See attached</p>", "replies": [{ "id": 51, "attachment": file_json(&server, 130, 0, "notes.txt") }] }],
+    })).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--discussions"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let discussions_folder = working_folder.path().join("downloads").join("CS101").join("discussions");
+    assert!(std::fs::read_to_string(discussions_folder.join("Week 1 Questions.html")).unwrap().contains("<p>Ask here</p>"));
+    assert!(std::fs::read_to_string(discussions_folder.join("Week 1 Questions.json")).unwrap().contains("See attached"));
+    assert_eq!(std::fs::read(discussions_folder.join("Week 1 Questions").join("notes.txt")).unwrap(), b"abc");
+}
+
+#[tokio::test]
+async fn adds_the_id_to_discussions_with_the_same_title() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    mount_json(&server, "/api/v1/courses/1/discussion_topics", json!([
+        { "id": 6, "title": "Questions", "message": "<p>Second</p>", "attachments": [] },
+        { "id": 5, "title": "Questions", "message": "<p>First</p>", "attachments": [] },
+    ])).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--discussions"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let discussions_folder = working_folder.path().join("downloads").join("CS101").join("discussions");
+    assert!(std::fs::read_to_string(discussions_folder.join("Questions.html")).unwrap().contains("<p>First</p>"));
+    assert!(std::fs::read_to_string(discussions_folder.join("Questions (6).html")).unwrap().contains("<p>Second</p>"));
+}
+
+#[tokio::test]
+async fn resumes_the_queue_with_the_files_logged_as_done() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+    let queue_path = working_folder.path().join("queue.json");
+    let log_path = working_folder.path().join("queue.json.log");
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--queue-file", "queue.json"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!log_path.exists());
+    let mut queue: Vec<serde_json::Value> = serde_json::from_slice(&std::fs::read(&queue_path).unwrap()).unwrap();
+    assert!(queue.iter().all(|entry| entry["status"] == "done"));
+
+    // as if the run was killed before it could save the queue
+    let log: String = queue.iter().map(|entry| format!("{}\n", entry)).collect();
+    std::fs::write(&log_path, log).unwrap();
+    for entry in &mut queue {
+        entry["status"] = json!("pending");
+    }
+    std::fs::write(&queue_path, serde_json::to_vec(&queue).unwrap()).unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--queue-file", "queue.json", "--resume"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!log_path.exists());
+}
+
+#[tokio::test]
+async fn dumps_the_walked_folders_and_files_as_metadata() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 0).await;
+    mount_download(&server, 120, 0).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--dry-run", "--dump-metadata", "metadata.json"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let metadata: serde_json::Value = serde_json::from_slice(&std::fs::read(working_folder.path().join("metadata.json")).unwrap()).unwrap();
+    let course = &metadata["courses"][0];
+    assert_eq!(course["course_code"], "CS101");
+    let root_folder = &course["folders"][0];
+    assert_eq!(root_folder["id"], 10);
+    assert_eq!(root_folder["files"][0]["display_name"], "syllabus.pdf");
+    let slides_folder = &root_folder["folders"][0]["folders"][0];
+    assert_eq!(slides_folder["parent_folder_id"], 11);
+    assert_eq!(slides_folder["files"][0]["id"], 120);
+    assert_eq!(slides_folder["files"][0]["size"], 3);
+}
+
+#[tokio::test]
+async fn skips_files_the_host_says_are_not_modified() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    Mock::given(method("HEAD"))
+        .and(path("/files/100/download"))
+        .and(header("If-None-Match", "\"v1\""))
+        .respond_with(ResponseTemplate::new(304))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/files/100/download"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(b"abc".to_vec()))
+        .expect(0)
+        .mount(&server)
+        .await;
+    Mock::given(method("HEAD"))
+        .and(path("/files/120/download"))
+        .respond_with(ResponseTemplate::new(200).insert_header("ETag", "\"head\""))
+        .mount(&server)
+        .await;
+    // the etag kept is the one that came with the content
+    Mock::given(method("GET"))
+        .and(path("/files/120/download"))
+        .respond_with(ResponseTemplate::new(200).insert_header("ETag", "\"v2\"").set_body_bytes(b"abc".to_vec()))
+        .mount(&server)
+        .await;
+    let working_folder = tempfile::tempdir().unwrap();
+    let destination_folder = working_folder.path().join("downloads");
+    let course_folder = destination_folder.join("CS101");
+    std::fs::create_dir_all(&course_folder).unwrap();
+    // an older copy than the one on canvas, so the file is looked at again
+    std::fs::write(course_folder.join("syllabus.pdf"), b"xyz").unwrap();
+    filetime::set_file_mtime(course_folder.join("syllabus.pdf"), filetime::FileTime::from_unix_time(0, 0)).unwrap();
+    std::fs::write(destination_folder.join(".canvas-etags.json"), r#"{"100": "\"v1\""}"#).unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "-n"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(course_folder.join("syllabus.pdf")).unwrap(), b"xyz");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 of the files was not modified"), "{}", String::from_utf8_lossy(&output.stdout));
+    let etags: serde_json::Value = serde_json::from_slice(&std::fs::read(destination_folder.join(".canvas-etags.json")).unwrap()).unwrap();
+    assert_eq!(etags, json!({ "100": "\"v1\"", "120": "\"v2\"" }));
+}
+
+#[tokio::test]
+async fn downloads_a_cut_short_file_again_despite_its_etag() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 120, 1).await;
+    // the stored etag is not sent, so the host cannot answer with a 304
+    Mock::given(header_exists("If-None-Match"))
+        .respond_with(ResponseTemplate::new(304))
+        .with_priority(1)
+        .expect(0)
+        .mount(&server)
+        .await;
+    mount_download(&server, 100, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+    let destination_folder = working_folder.path().join("downloads");
+    let course_folder = destination_folder.join("CS101");
+    std::fs::create_dir_all(&course_folder).unwrap();
+    // the size does not match the one on canvas, as if the last download was cut short
+    std::fs::write(course_folder.join("syllabus.pdf"), b"ab").unwrap();
+    std::fs::write(destination_folder.join(".canvas-etags.json"), r#"{"100": "\"v1\""}"#).unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(course_folder.join("syllabus.pdf")).unwrap(), b"abc");
+}
+
+#[tokio::test]
+async fn follows_file_redirects_without_sending_the_token_to_the_storage_host() {
+    let server = MockServer::start().await;
+    let storage = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 120, 1).await;
+    let signed_url = format!("{}/signed/100?signature=abc", storage.uri());
+    Mock::given(method("HEAD"))
+        .and(path("/files/100/download"))
+        .and(header("Authorization", "Bearer token"))
+        .respond_with(ResponseTemplate::new(302).insert_header("Location", signed_url.as_str()))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // the download goes straight to where the HEAD request was redirected
+    Mock::given(method("GET"))
+        .and(path("/files/100/download"))
+        .respond_with(ResponseTemplate::new(302).insert_header("Location", signed_url.as_str()))
+        .expect(0)
+        .mount(&server)
+        .await;
+    Mock::given(header_exists("Authorization"))
+        .respond_with(ResponseTemplate::new(400))
+        .with_priority(1)
+        .expect(0)
+        .mount(&storage)
+        .await;
+    for request_method in ["HEAD", "GET"] {
+        Mock::given(method(request_method))
+            .and(path("/signed/100"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"abc".to_vec()))
+            .expect(1)
+            .mount(&storage)
+            .await;
+    }
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(working_folder.path().join("downloads/CS101/syllabus.pdf")).unwrap(), b"abc");
+}
+
+#[tokio::test]
+async fn skips_files_without_a_download_url() {
+    let server = MockServer::start().await;
+    let mut uploading = file_json(&server, 110, 11, "draft.pdf");
+    uploading["url"] = serde_json::Value::Null;
+    // mounted first, so it answers instead of the empty folder in the course
+    mount_json(&server, "/api/v1/folders/11/files", json!([uploading])).await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!working_folder.path().join("downloads/CS101/Week 1 Intro/draft.pdf").exists());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("draft.pdf"), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[tokio::test]
+async fn lays_files_out_with_the_destination_template() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--destination-template", "{term}/{course_name}/{folder_path}/{filename}"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let course_folder = working_folder.path().join("downloads").join("No term").join("Intro to CS");
+    assert!(course_folder.join("syllabus.pdf").is_file());
+    assert!(course_folder.join("Week 1 Intro").join("Slides").join("ab.pdf").is_file());
+    assert!(!working_folder.path().join("downloads").join("CS101").exists());
+}
+
+#[tokio::test]
+async fn rejects_unknown_placeholders_in_the_destination_template() {
+    let server = MockServer::start().await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["--destination-template", "{course}/{filename}"]).await;
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("{course} is not a placeholder"), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[tokio::test]
+async fn login_saves_the_credentials_without_downloading() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 0).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    // the global options can also go before the subcommand
+    let output = run(&server, working_folder.path(), &["login", "-c", "credentials.json"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let credentials: serde_json::Value = serde_json::from_slice(&std::fs::read(working_folder.path().join("credentials.json")).unwrap()).unwrap();
+    assert_eq!(credentials, json!({ "canvasUrl": server.uri(), "canvasToken": "token" }));
+    assert!(!working_folder.path().join("CS101").exists());
+}
+
+#[tokio::test]
+async fn warns_about_a_token_on_the_command_line_and_leaves_it_out_of_errors() {
+    let server = MockServer::start().await;
+    let token = "7~kX2pQ9rT4vW8yZ1bN6mC3dF5gH0jL";
+    Mock::given(method("GET"))
+        .and(path("/api/v1/users/self"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({ "errors": [{ "message": format!("Invalid access token {}", token) }] })))
+        .mount(&server)
+        .await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = tokio::process::Command::new(env!("CARGO_BIN_EXE_canvas-downloader"))
+        .args(["-u", &server.uri(), "-t", token, "-d", "downloads"])
+        .current_dir(working_folder.path())
+        .env("XDG_CONFIG_HOME", working_folder.path())
+        .output()
+        .await
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("is now in the shell history"), "{}", stderr);
+    assert!(stderr.contains("Invalid access token [REDACTED]"), "{}", stderr);
+    assert!(!stderr.contains(token), "{}", stderr);
+}
+
+#[tokio::test]
+async fn only_new_skips_files_downloaded_before_even_when_deleted() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--only-new"]).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let syllabus_path = working_folder.path().join("downloads/CS101/syllabus.pdf");
+    std::fs::remove_file(&syllabus_path).unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--only-new"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!syllabus_path.exists());
+    assert!(working_folder.path().join("downloads/.canvas-seen.json").is_file());
+}
+
+#[tokio::test]
+async fn keeps_media_recordings_under_the_same_id_between_runs() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    mount_json(&server, "/api/v1/courses/1/media_objects", json!([
+        {
+            "media_id": "m-first",
+            "title": "Lecture 1",
+            "media_sources": [{ "url": format!("{}/media/first.mp4", server.uri()), "size": "1", "fileExt": "mp4" }],
+        },
+        {
+            "media_id": "m-second",
+            "title": "Lecture 2",
+            "media_sources": [{ "url": format!("{}/media/second.mp4", server.uri()), "size": "1", "fileExt": "mp4" }],
+        },
+    ])).await;
+    for request_method in ["HEAD", "GET"] {
+        Mock::given(method(request_method))
+            .and(path("/media/first.mp4"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"abc".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method(request_method))
+            .and(path("/media/second.mp4"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"abc".to_vec()))
+            .expect(0)
+            .mount(&server)
+            .await;
+    }
+    let working_folder = tempfile::tempdir().unwrap();
+    let recording_path = working_folder.path().join("downloads/CS101/Media/Lecture 1.mp4");
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--media", "--only-new", "--exclude", "**/Lecture 2.mp4"]).await;
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(recording_path.is_file());
+    std::fs::remove_file(&recording_path).unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--media", "--only-new", "--exclude", "**/Lecture 2.mp4"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!recording_path.exists());
+}
+
+#[tokio::test]
+async fn creates_empty_files_without_downloading_them() {
+    let server = MockServer::start().await;
+    let mut empty = file_json(&server, 110, 11, "blank.txt");
+    empty["size"] = json!(0);
+    // mounted first, so it answers instead of the empty folder in the course
+    mount_json(&server, "/api/v1/folders/11/files", json!([empty])).await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 110, 0).await;
+    mount_download(&server, 120, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let blank = std::fs::metadata(working_folder.path().join("downloads/CS101/Week 1 Intro/blank.txt")).unwrap();
+    assert_eq!(blank.len(), 0);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1 of the files was empty on canvas"), "{}", String::from_utf8_lossy(&output.stdout));
+}
+
+// run straight from the command line arguments, without a mock canvas to talk to
+fn canvas_downloader(working_folder: &Path) -> Command {
+    let mut command = Command::cargo_bin("canvas-downloader").unwrap();
+    command.current_dir(working_folder)
+        .env("XDG_CONFIG_HOME", working_folder)
+        .env_remove("CANVAS_URL")
+        .env_remove("CANVAS_TOKEN")
+        // what a user sees, whatever the shell running the tests has set
+        .env_remove("RUST_BACKTRACE")
+        .env_remove("RUST_LIB_BACKTRACE");
+    command
+}
+
+#[test]
+fn exits_with_bad_arguments_for_each_missing_argument() {
+    let working_folder = tempfile::tempdir().unwrap();
+    let cases: &[(&[&str], &str)] = &[
+        (&["-d", "downloads"], "Provide canvas url and token"),
+        (&["-u", "https://canvas.example.com", "-d", "downloads"], "Provide canvas url and token"),
+        // saving credentials needs somewhere to save them to
+        (&["-u", "https://canvas.example.com", "-t", "a-canvas-token", "-s", "-d", "downloads"], "--canvas-credential-path"),
+        (&["-c", "missing.json", "-d", "downloads"], "The given path to the credentials file does not exist"),
+    ];
+
+    for (args, message) in cases {
+        let output = canvas_downloader(working_folder.path()).args(*args).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert_eq!(output.status.code(), Some(2), "{:?}: {}", args, stderr);
+        assert!(stderr.contains(message), "{:?}: {}", args, stderr);
+        assert!(!stderr.contains("panicked") && !stderr.contains("backtrace"), "{:?}: {}", args, stderr);
+    }
+}
+
+#[tokio::test]
+async fn exits_with_authentication_for_a_token_canvas_turns_down() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/users/self"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({ "errors": [{ "message": "Invalid access token." }] })))
+        .mount(&server)
+        .await;
+    let working_folder = tempfile::tempdir().unwrap();
+    let uri = server.uri();
+
+    let assert = tokio::task::spawn_blocking(move || canvas_downloader(working_folder.path())
+        .args(["-u", &uri, "-t", "expired-token", "-d", "downloads"])
+        .assert())
+        .await
+        .unwrap();
+
+    assert.code(3);
+}
+
+#[tokio::test]
+async fn since_keeps_files_without_an_updated_date() {
+    let server = MockServer::start().await;
+    let mut undated = file_json(&server, 110, 11, "undated.pdf");
+    undated["updated_at"] = serde_json::Value::Null;
+    // mounted first, so it answers instead of the empty folder in the course
+    mount_json(&server, "/api/v1/folders/11/files", json!([undated])).await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 0).await;
+    mount_download(&server, 110, 1).await;
+    mount_download(&server, 120, 0).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--since", "2025-01-01"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(working_folder.path().join("downloads/CS101/Week 1 Intro/undated.pdf").is_file());
+    assert!(working_folder.path().join("downloads/.last-sync").is_file());
+}
+
+#[tokio::test]
+async fn asks_canvas_for_a_new_download_url_once_the_signed_one_expires() {
+    let server = MockServer::start().await;
+    let storage = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 120, 1).await;
+    let expired_url = format!("{}/signed/100?signature=old", storage.uri());
+    let fresh_url = format!("{}/signed/100?signature=new", storage.uri());
+    Mock::given(method("HEAD"))
+        .and(path("/files/100/download"))
+        .respond_with(ResponseTemplate::new(302).insert_header("Location", expired_url.as_str()))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/files/100/download"))
+        .and(header("Authorization", "Bearer token"))
+        .respond_with(ResponseTemplate::new(302).insert_header("Location", fresh_url.as_str()))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("HEAD"))
+        .and(path("/signed/100"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(b"abc".to_vec()))
+        .mount(&storage)
+        .await;
+    // as if the signature ran out between the HEAD and the GET
+    Mock::given(method("GET"))
+        .and(path("/signed/100"))
+        .and(query_param("signature", "old"))
+        .respond_with(ResponseTemplate::new(403))
+        .expect(1)
+        .mount(&storage)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/signed/100"))
+        .and(query_param("signature", "new"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(b"abc".to_vec()))
+        .expect(1)
+        .mount(&storage)
+        .await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(working_folder.path().join("downloads/CS101/syllabus.pdf")).unwrap(), b"abc");
+}
+
+#[tokio::test]
+async fn dry_run_with_a_course_map_creates_no_folders() {
+    let server = MockServer::start().await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 0).await;
+    mount_download(&server, 120, 0).await;
+    let working_folder = tempfile::tempdir().unwrap();
+    std::fs::write(working_folder.path().join("courses.json"), r#"{"1": "mapped/cs101"}"#).unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--course-map", "courses.json", "--dry-run"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("syllabus.pdf"), "{}", String::from_utf8_lossy(&output.stdout));
+    assert!(!working_folder.path().join("mapped").exists());
+    assert!(!working_folder.path().join("downloads").exists());
+}
+
+#[tokio::test]
+async fn saves_the_front_page_as_markdown() {
+    let server = MockServer::start().await;
+    // mounted first, so it answers instead of the course without a front page
+    mount_json(&server, "/api/v1/courses", json!([{ "id": 1, "name": "Intro to CS", "course_code": "CS101", "default_view": "wiki", "enrollments": [{}] }])).await;
+    mount_json(&server, "/api/v1/courses/1/front_page", json!({
+        "page_id": 7,
+        "url": "welcome",
+        "title": "Welcome",
+        "body": "<p>Office hours are on <strong>Monday</strong></p>",
+        "updated_at": "2024-02-01T10:00:00Z",
+    })).await;
+    mount_course(&server).await;
+    mount_download(&server, 100, 1).await;
+    mount_download(&server, 120, 1).await;
+    let working_folder = tempfile::tempdir().unwrap();
+
+    let output = run(&server, working_folder.path(), &["-d", "downloads", "--front-page", "--html-to-markdown"]).await;
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let course_folder = working_folder.path().join("downloads/CS101");
+    assert_eq!(std::fs::read_to_string(course_folder.join("home.md")).unwrap(), "# Welcome\n\nOffice hours are on **Monday**\n");
+    assert!(!course_folder.join("home.html").exists());
+}
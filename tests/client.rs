@@ -0,0 +1,147 @@
+use canvas_downloader::{canvas, CanvasClient};
+use serde_json::json;
+use wiremock::matchers::{header, method, path, query_param, query_param_is_missing};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+fn canvas_client(server: &MockServer) -> CanvasClient {
+    CanvasClient::new(reqwest::Client::new(), server.uri(), "token")
+}
+
+fn file_json(server: &MockServer, id: u32) -> serde_json::Value {
+    json!({
+        "id": id,
+        "folder_id": 1,
+        "display_name": format!("file{}.pdf", id),
+        "size": 3,
+        "url": format!("{}/files/{}/download", server.uri(), id),
+        "updated_at": "2024-02-01T10:00:00Z",
+    })
+}
+
+#[tokio::test]
+async fn follows_next_links_across_pages() {
+    let server = MockServer::start().await;
+    let next_link = format!("<{}/api/v1/folders/1/files?page=bookmark:WzEwXQ&per_page=100>; rel=\"next\"", server.uri());
+    Mock::given(method("GET"))
+        .and(path("/api/v1/folders/1/files"))
+        .and(query_param_is_missing("page"))
+        .and(query_param("per_page", "100"))
+        .and(header("authorization", "Bearer token"))
+        .respond_with(ResponseTemplate::new(200)
+            .set_body_json(json!([file_json(&server, 1), file_json(&server, 2)]))
+            .insert_header("link", next_link.as_str()))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/folders/1/files"))
+        .and(query_param("page", "bookmark:WzEwXQ"))
+        .and(header("authorization", "Bearer token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([file_json(&server, 3)])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let files = canvas_client(&server).list_files(1).await.unwrap();
+
+    assert_eq!(files.iter().map(|file| file.id).collect::<Vec<_>>(), vec![1, 2, 3]);
+}
+
+#[tokio::test]
+async fn counts_restricted_and_null_courses() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/courses"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+            { "id": 1, "name": "Intro to CS", "course_code": "CS101", "enrollments": [{}] },
+            { "id": 2, "access_restricted_by_date": true },
+            null,
+        ])))
+        .mount(&server)
+        .await;
+
+    let course_list = canvas_client(&server).list_courses().await.unwrap();
+
+    assert_eq!(course_list.courses.iter().map(|course| course.id).collect::<Vec<_>>(), vec![1]);
+    assert_eq!(course_list.access_restricted, 1);
+    assert_eq!(course_list.inaccessible, 1);
+}
+
+#[tokio::test]
+async fn lists_no_courses_for_an_empty_array() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/courses"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([])))
+        .mount(&server)
+        .await;
+
+    let course_list = canvas_client(&server).list_courses().await.unwrap();
+
+    assert!(course_list.courses.is_empty());
+    assert_eq!(course_list.access_restricted, 0);
+    assert_eq!(course_list.inaccessible, 0);
+}
+
+#[tokio::test]
+async fn reads_errors_given_instead_of_a_list_of_courses() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/courses"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "errors": [{ "message": "user not authorized to perform that action" }] })))
+        .mount(&server)
+        .await;
+
+    let e = canvas_client(&server).list_courses().await.err().expect("an errors body is an error");
+    let canvas_error = e.downcast_ref::<canvas::CanvasError>().unwrap();
+
+    assert_eq!(canvas_error.messages, vec!["user not authorized to perform that action"]);
+}
+
+#[tokio::test]
+async fn reads_the_error_envelope() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/users/self"))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({ "errors": [{ "message": "Invalid access token." }] })))
+        .mount(&server)
+        .await;
+
+    let e = canvas_client(&server).user().await.err().expect("an invalid token is an error");
+    let canvas_error = e.downcast_ref::<canvas::CanvasError>().unwrap();
+
+    assert!(canvas_error.is_unauthorized());
+    assert_eq!(canvas_error.messages, vec!["Invalid access token."]);
+}
+
+#[tokio::test]
+async fn reads_an_error_status_given_instead_of_a_list() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/folders/1/files"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "status": "unauthorized" })))
+        .mount(&server)
+        .await;
+
+    let e = canvas_client(&server).list_files(1).await.unwrap_err();
+    let canvas_error = e.downcast_ref::<canvas::CanvasError>().unwrap();
+
+    assert!(canvas_error.is_unauthorized());
+    assert_eq!(canvas_error.canvas_status.as_deref(), Some("unauthorized"));
+}
+
+#[tokio::test]
+async fn clamps_the_page_size_to_the_canvas_maximum() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/folders/1/files"))
+        .and(query_param("per_page", "100"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!([])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let files = canvas_client(&server).with_per_page(500).list_files(1).await.unwrap();
+
+    assert!(files.is_empty());
+}